@@ -1,8 +1,13 @@
 #!/bin/bpftrace
 
+usdt:./$1:perfspan:register
+{
+    printf("register %lx %s %s\n", arg0, str(arg2, arg1), str(arg4, arg3));
+}
+
 usdt:./$1:perfspan:enter
 {
-    printf("enter %lx %lx %s\n", arg0, arg1, str(arg2));
+    printf("enter %lx callsite %lx fields %d\n", arg0, arg1, arg2);
 }
 
 usdt:./$1:perfspan:exit
@@ -7,21 +7,117 @@
 
 #include "perfspan.h"
 
+// processes, threads, thread names and cgroups that are monitored. filters are enabled in filter_config,
+// and every enabled filter must match. userspace updates them while the program runs
 struct
 {
     __uint(type, BPF_MAP_TYPE_HASH);
-    __type(key, __u8[MAX_NAME_SIZE]);
+    __type(key, u32);
+    __type(value, u8);
+    __uint(max_entries, 4096);
+} filter_tgids SEC(".maps");
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, u32);
+    __type(value, u8);
+    __uint(max_entries, 4096);
+} filter_tids SEC(".maps");
+
+struct comm_key
+{
+    u32 prefixlen;
+    u8 comm[MAX_COMM_SIZE];
+};
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __type(key, struct comm_key);
+    __type(value, u8);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+    __uint(max_entries, 256);
+} filter_comms SEC(".maps");
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, u64);
+    __type(value, u8);
+    __uint(max_entries, 256);
+} filter_cgroups SEC(".maps");
+
+struct filter_config
+{
+    u32 tgids;
+    u32 tids;
+    u32 comms;
+    u32 cgroups;
+};
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __type(key, u32);
+    __type(value, struct filter_config);
+    __uint(max_entries, 1);
+} filter_config SEC(".maps");
+
+// name=value pairs that must be present in the fields of the span, value is the index of the predicate
+struct
+{
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, __u8[MAX_PREDICATE_SIZE]);
     __type(value, __u8);
-    __uint(max_entries, 32);
-} filter_by_name SEC(".maps");
+    __uint(max_entries, MAX_PREDICATES);
+} filter_by_field SEC(".maps");
+
+// callsites of the spans in every process. value is the index of the watched span, or one of the markers
+// for callsites that are not watched or that are being resolved by userspace
+#define CALLSITE_UNWATCHED 0xff
+#define CALLSITE_PENDING 0xfe
+
+struct callsite_key
+{
+    u32 tgid;
+    u32 pad;
+    u64 callsite;
+};
 
 struct
 {
-    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct callsite_key);
+    __type(value, u8);
+    __uint(max_entries, 16384);
+} callsites SEC(".maps");
+
+// metrics recorded with perfspan:value, keyed by the address and size of the name literal in the process.
+// value is the index of the metric, or one of the callsite markers
+struct metric_key
+{
+    u32 tgid;
+    u32 name_size;
+    u64 name;
+};
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct metric_key);
+    __type(value, u8);
+    __uint(max_entries, 4096);
+} metrics SEC(".maps");
+
+// perf counters opened on every cpu, counter i of the cpu is at i * nr_cpus + cpu.
+// max_entries is set by userspace to MAX_EVENTS * nr_cpus
+struct
+{
+    __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
     __uint(key_size, sizeof(u32));
-    __uint(value_size, sizeof(u64));
-    __uint(max_entries, MAX_EVENTS);
-} perf_events SEC(".maps");
+    __uint(value_size, sizeof(u32));
+} counters SEC(".maps");
 
 struct
 {
@@ -29,93 +125,558 @@ struct
     __uint(max_entries, 8 << 20);
 } events SEC(".maps");
 
+// records are assembled in the scratch space and copied into the ring buffer with the size
+// of the counters and fields that were read
+struct record_buf
+{
+    struct record header;
+    u8 data[MAX_EVENTS * sizeof(struct counter_value) + MAX_FIELDS_SIZE];
+};
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __type(key, u32);
+    __type(value, struct record_buf);
+    __uint(max_entries, 1);
+} record_scratch SEC(".maps");
+
+// watched spans that the thread is currently in, the last one is the innermost
+struct active_spans
+{
+    u32 depth;
+    u8 name_ids[MAX_ACTIVE_DEPTH];
+};
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, u64);
+    __type(value, struct active_spans);
+    __uint(max_entries, 16384);
+} active_spans SEC(".maps");
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_STACK_TRACE);
+    __uint(key_size, sizeof(u32));
+    __uint(value_size, PERF_MAX_STACK_DEPTH * sizeof(u64));
+    __uint(max_entries, 16384);
+} stack_traces SEC(".maps");
+
+struct
+{
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct stack_key);
+    __type(value, u64);
+    __uint(max_entries, 16384);
+} stack_counts SEC(".maps");
+
 const volatile struct
 {
     u32 enabled_events;
-    u32 filter_tgid;
+    u32 nr_cpus;
+    u32 follow_children;
+    u32 profile;
+    u32 profile_kernel;
+    u32 read_fields;
+    u32 predicates;
 } cfg = {
     .enabled_events = 0,
-    .filter_tgid = 0,
+    .nr_cpus = 0,
+    .follow_children = 0,
+    .profile = 0,
+    .profile_kernel = 0,
+    .read_fields = 0,
+    .predicates = 0,
 };
 
 SEC("perf_event")
-int on_perf_event(struct bpf_perf_event_data *ctx)
+int on_profile(struct bpf_perf_event_data *ctx)
 {
-    u32 cookie = bpf_get_attach_cookie(ctx);
-    u64 sample_period = ctx->sample_period;
-    u64 *val = bpf_map_lookup_elem(&perf_events, &cookie);
-    if (val)
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct active_spans *active = bpf_map_lookup_elem(&active_spans, &pid_tgid);
+    if (!active)
+    {
+        return 0;
+    }
+    u32 depth = active->depth;
+    if (depth == 0)
+    {
+        return 0;
+    }
+    // spans nested deeper than MAX_ACTIVE_DEPTH are attributed to the last tracked span
+    u32 top = depth > MAX_ACTIVE_DEPTH ? MAX_ACTIVE_DEPTH - 1 : depth - 1;
+    if (top >= MAX_ACTIVE_DEPTH)
+    {
+        return 0;
+    }
+    struct stack_key key = {};
+    key.tgid = pid_tgid >> 32;
+    key.name_id = active->name_ids[top];
+    key.user_stack_id = bpf_get_stackid(ctx, &stack_traces, BPF_F_USER_STACK);
+    key.kernel_stack_id = cfg.profile_kernel ? bpf_get_stackid(ctx, &stack_traces, 0) : -1;
+
+    u64 *count = bpf_map_lookup_elem(&stack_counts, &key);
+    if (count)
     {
-        *val += sample_period;
+        __sync_fetch_and_add(count, 1);
     }
     else
     {
-        bpf_map_update_elem(&perf_events, &cookie, &sample_period, BPF_ANY);
+        u64 one = 1;
+        bpf_map_update_elem(&stack_counts, &key, &one, BPF_NOEXIST);
     }
     return 0;
 }
 
-__always_inline int try_submit_event(u8 event_type, u64 span_id, u64 name_size, char *name)
+__always_inline void track_active_span(u8 event_type, u64 pid_tgid, u8 name_id)
 {
-    u64 pid_tgid = bpf_get_current_pid_tgid();
-    if (cfg.filter_tgid != 0 && pid_tgid >> 32 != cfg.filter_tgid)
+    struct active_spans *active = bpf_map_lookup_elem(&active_spans, &pid_tgid);
+    if (event_type == ENTER)
+    {
+        if (!active)
+        {
+            struct active_spans init = {};
+            init.depth = 1;
+            init.name_ids[0] = name_id;
+            bpf_map_update_elem(&active_spans, &pid_tgid, &init, BPF_ANY);
+            return;
+        }
+        u32 depth = active->depth;
+        if (depth < MAX_ACTIVE_DEPTH)
+        {
+            active->name_ids[depth] = name_id;
+        }
+        active->depth = depth + 1;
+    }
+    else if (active)
+    {
+        if (active->depth <= 1)
+        {
+            bpf_map_delete_elem(&active_spans, &pid_tgid);
+        }
+        else
+        {
+            active->depth--;
+        }
+    }
+}
+
+// children of the monitored processes are added to the filter when they are forked, exec keeps the tgid
+// so they remain monitored after exec. threads share tgid with the parent and are skipped
+SEC("tp_btf/sched_process_fork")
+int BPF_PROG(on_process_fork, struct task_struct *parent, struct task_struct *child)
+{
+    u32 parent_tgid = parent->tgid;
+    u32 child_tgid = child->tgid;
+    if (!cfg.follow_children || parent_tgid == child_tgid)
+    {
+        return 0;
+    }
+    if (!bpf_map_lookup_elem(&filter_tgids, &parent_tgid))
+    {
+        return 0;
+    }
+    u8 one = 1;
+    bpf_map_update_elem(&filter_tgids, &child_tgid, &one, BPF_ANY);
+    return 0;
+}
+
+// processes are removed once the main thread exits, so that reused pids are not monitored
+SEC("tp_btf/sched_process_exit")
+int BPF_PROG(on_process_exit, struct task_struct *task)
+{
+    if (!cfg.follow_children || task->pid != task->tgid)
     {
         return 0;
     }
+    u32 tgid = task->tgid;
+    bpf_map_delete_elem(&filter_tgids, &tgid);
+    return 0;
+}
+
+// returns scratch space for the record with the header zeroed, type, cpu, thread and time are set
+__always_inline struct record_buf *start_record(u8 type, u64 pid_tgid)
+{
+    u32 zero = 0;
+    struct record_buf *buf = bpf_map_lookup_elem(&record_scratch, &zero);
+    if (!buf)
+    {
+        return NULL;
+    }
+    __builtin_memset(&buf->header, 0, sizeof(buf->header));
+    buf->header.type = type;
+    buf->header.cpu = bpf_get_smp_processor_id();
+    buf->header.pid_tgid = pid_tgid;
+    buf->header.timestamp = bpf_ktime_get_ns();
+    return buf;
+}
+
+// submits the header and the first data_size bytes of the data
+__always_inline int submit_record(struct record_buf *buf, u64 data_size)
+{
+    if (data_size > sizeof(buf->data))
+    {
+        return 1;
+    }
+    if (bpf_ringbuf_output(&events, buf, sizeof(buf->header) + data_size, 0) != 0)
+    {
+        bpf_printk("ringbuf_output failed\n");
+        return 1;
+    }
+    return 0;
+}
+
+// reads at most MAX_FIELDS_SIZE bytes of fields from the user memory into the data at the offset,
+// returns the number of bytes read
+__always_inline u64 read_fields(struct record_buf *buf, u64 offset, u64 size, char *src)
+{
+    if (size > MAX_FIELDS_SIZE)
+    {
+        size = MAX_FIELDS_SIZE;
+    }
+    if (offset > sizeof(buf->data) - MAX_FIELDS_SIZE)
+    {
+        return 0;
+    }
+    if (bpf_probe_read_user(buf->data + offset, size, src) != 0)
+    {
+        return 0;
+    }
+    buf->header.fields_size = size;
+    return size;
+}
+
+__always_inline bool match_filters(u64 pid_tgid)
+{
+    u32 zero = 0;
+    struct filter_config *config = bpf_map_lookup_elem(&filter_config, &zero);
+    if (!config)
+    {
+        return false;
+    }
+    u32 tgid = pid_tgid >> 32;
+    u32 tid = pid_tgid;
+    if (config->tgids && !bpf_map_lookup_elem(&filter_tgids, &tgid))
+    {
+        return false;
+    }
+    if (config->tids && !bpf_map_lookup_elem(&filter_tids, &tid))
+    {
+        return false;
+    }
+    if (config->comms)
+    {
+        struct comm_key key = {.prefixlen = MAX_COMM_SIZE * 8};
+        bpf_get_current_comm(&key.comm, sizeof(key.comm));
+        if (!bpf_map_lookup_elem(&filter_comms, &key))
+        {
+            return false;
+        }
+    }
+    if (config->cgroups)
+    {
+        u64 cgroup_id = bpf_get_current_cgroup_id();
+        if (!bpf_map_lookup_elem(&filter_cgroups, &cgroup_id))
+        {
+            return false;
+        }
+    }
+    return true;
+}
+
+// checks that every predicate matches one of the name=value pairs in the fields
+__always_inline bool match_predicates(u8 *fields)
+{
+    u8 key[MAX_PREDICATE_SIZE];
+    u32 matched = 0;
+    u32 start = 0;
+    for (u32 i = 0; i < MAX_FIELDS_SIZE; i++)
+    {
+        if (fields[i] != 0)
+        {
+            continue;
+        }
+        u32 size = i - start;
+        if (size > 0 && size <= MAX_PREDICATE_SIZE && start < MAX_FIELDS_SIZE)
+        {
+            __builtin_memset(key, 0, sizeof(key));
+            bpf_probe_read_kernel(key, size, fields + start);
+            u8 *index = bpf_map_lookup_elem(&filter_by_field, &key);
+            if (index && *index < MAX_PREDICATES)
+            {
+                matched |= 1 << *index;
+            }
+        }
+        start = i + 1;
+    }
+    return matched == (1 << cfg.predicates) - 1;
+}
+
+// resolves callsite to the index of the watched span. callsites that are not known yet are sent to userspace once,
+// they are dropped until userspace reads their names from the memory of the process
+__always_inline u8 *lookup_callsite(u64 pid_tgid, u64 callsite)
+{
+    struct callsite_key key = {.tgid = pid_tgid >> 32, .callsite = callsite};
+    u8 *name_id = bpf_map_lookup_elem(&callsites, &key);
+    if (name_id)
+    {
+        return *name_id < CALLSITE_PENDING ? name_id : NULL;
+    }
+    u8 pending = CALLSITE_PENDING;
+    if (bpf_map_update_elem(&callsites, &key, &pending, BPF_NOEXIST) != 0)
+    {
+        return NULL;
+    }
+    struct record_buf *buf = start_record(UNKNOWN_CALLSITE, pid_tgid);
+    if (!buf)
+    {
+        bpf_map_delete_elem(&callsites, &key);
+        return NULL;
+    }
+    buf->header.span_id = callsite;
+    if (submit_record(buf, 0) != 0)
+    {
+        bpf_map_delete_elem(&callsites, &key);
+    }
+    return NULL;
+}
 
-    __u8 span_name[MAX_NAME_SIZE] = {0};
-    if (name_size > MAX_NAME_SIZE)
+__always_inline int try_submit_event(u8 event_type, u64 span_id, u64 callsite, u64 fields_size,
+                                     char *fields)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    if (!match_filters(pid_tgid))
     {
-        name_size = MAX_NAME_SIZE;
+        return 0;
     }
-    bpf_probe_read_user(&span_name, name_size, name);
-    __u8 *name_id = bpf_map_lookup_elem(&filter_by_name, &span_name);
+
+    __u8 *name_id = lookup_callsite(pid_tgid, callsite);
     if (!name_id)
     {
         return 0;
     }
 
-    u64 timestamp = bpf_ktime_get_ns();
+    if (cfg.profile)
+    {
+        track_active_span(event_type, pid_tgid, *name_id);
+    }
 
-    struct event *ev = bpf_ringbuf_reserve(&events, sizeof(struct event), 0);
-    if (!ev)
+    struct record_buf *buf = start_record(event_type, pid_tgid);
+    if (!buf)
     {
-        bpf_printk("ringbuf_reserve failed\n");
         return 1;
     }
-    ev->type = event_type;
-    ev->cpu = bpf_get_smp_processor_id();
-    ev->name_id = *name_id;
-    ev->span_id = span_id;
-    ev->pid_tgid = pid_tgid;
-    ev->timestamp = timestamp;
-    __u32 captured_i;
-    for (u32 i = 0; i < cfg.enabled_events; i++)
-    {
-        captured_i = i;
-        u64 *val = bpf_map_lookup_elem(&perf_events, &captured_i);
-        if (val)
+    buf->header.name_id = *name_id;
+    buf->header.span_id = span_id;
+
+    // predicates are checked on exit, as fields are often recorded after the span was entered.
+    // exit of the span that doesn't match is still sent without counters and fields, so that userspace closes
+    // it without recording
+    __u8 span_fields[MAX_FIELDS_SIZE] = {0};
+    if (cfg.read_fields)
+    {
+        if (fields_size > MAX_FIELDS_SIZE)
+        {
+            fields_size = MAX_FIELDS_SIZE;
+        }
+        if (bpf_probe_read_user(&span_fields, fields_size, fields) != 0)
+        {
+            fields_size = 0;
+        }
+        if (event_type == EXIT && cfg.predicates && !match_predicates(span_fields))
+        {
+            buf->header.type = FILTERED_EXIT;
+            return submit_record(buf, 0);
+        }
+    }
+
+    // counters are read together with the time they were enabled and running, so that spans are scaled
+    // by the time the counter was multiplexed within the span
+    u32 nr_counters = cfg.enabled_events < MAX_EVENTS ? cfg.enabled_events : MAX_EVENTS;
+    struct counter_value *values = (struct counter_value *)buf->data;
+    for (u32 i = 0; i < nr_counters && i < MAX_EVENTS; i++)
+    {
+        struct bpf_perf_event_value value = {};
+        if (bpf_perf_event_read_value(&counters, i * cfg.nr_cpus + buf->header.cpu, &value, sizeof(value)) != 0)
         {
-            ev->counters[i] = *val;
+            // counters that can't be read are reported as not running
+            __builtin_memset(&value, 0, sizeof(value));
         }
+        values[i].counter = value.counter;
+        values[i].enabled = value.enabled;
+        values[i].running = value.running;
+    }
+    buf->header.nr_counters = nr_counters;
+    u64 size = nr_counters * sizeof(struct counter_value);
+    if (cfg.read_fields && fields_size > 0 && size <= sizeof(buf->data) - MAX_FIELDS_SIZE)
+    {
+        bpf_probe_read_kernel(buf->data + size, fields_size, span_fields);
+        buf->header.fields_size = fields_size;
+        size += fields_size;
     }
+    return submit_record(buf, size);
+}
+
+SEC("usdt")
+int BPF_USDT(perfspan_enter, u64 span_id, u64 callsite, u64 fields_size, char *fields)
+{
+    return try_submit_event(ENTER, span_id, callsite, fields_size, fields);
+}
+
+SEC("usdt")
+int BPF_USDT(perfspan_exit, u64 span_id, u64 callsite, u64 fields_size, char *fields)
+{
+    return try_submit_event(EXIT, span_id, callsite, fields_size, fields);
+}
 
-    bpf_ringbuf_submit(ev, 0);
+// attached only to increment the semaphore of the probe, so that the layer formats fields of new spans
+SEC("usdt")
+int BPF_USDT(perfspan_new, u64 span_id, u64 callsite, u64 fields_size, char *fields)
+{
     return 0;
 }
 
+// values recorded after the span was created, they are merged by userspace into the open span
+SEC("usdt")
+int BPF_USDT(perfspan_record, u64 span_id, u64 callsite, u64 fields_size, char *fields)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    if (!match_filters(pid_tgid))
+    {
+        return 0;
+    }
+    u8 *name_id = lookup_callsite(pid_tgid, callsite);
+    if (!name_id)
+    {
+        return 0;
+    }
+    struct record_buf *buf = start_record(RECORD, pid_tgid);
+    if (!buf)
+    {
+        return 1;
+    }
+    buf->header.name_id = *name_id;
+    buf->header.span_id = span_id;
+    return submit_record(buf, read_fields(buf, 0, fields_size, fields));
+}
+
+// links are submitted only if both spans are watched, userspace measures latency from the enter of the cause
+SEC("usdt")
+int BPF_USDT(perfspan_follows, u64 span_id, u64 callsite, u64 cause_id, u64 cause_callsite)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    if (!match_filters(pid_tgid))
+    {
+        return 0;
+    }
+    u8 *name_id = lookup_callsite(pid_tgid, callsite);
+    u8 *cause_name_id = lookup_callsite(pid_tgid, cause_callsite);
+    if (!name_id || !cause_name_id)
+    {
+        return 0;
+    }
+    struct record_buf *buf = start_record(FOLLOWS, pid_tgid);
+    if (!buf)
+    {
+        return 1;
+    }
+    buf->header.name_id = *name_id;
+    buf->header.cause_name_id = *cause_name_id;
+    buf->header.span_id = span_id;
+    buf->header.value = cause_id;
+    return submit_record(buf, 0);
+}
+
+// events are submitted only with the watched span, the layer passes the nearest enclosing span that is watched
+SEC("usdt")
+int BPF_USDT(perfspan_event, u64 span_id, u64 callsite, u64 level, u64 message_size, char *message)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    if (span_id == 0 || !match_filters(pid_tgid))
+    {
+        return 0;
+    }
+    u8 *name_id = lookup_callsite(pid_tgid, callsite);
+    if (!name_id)
+    {
+        return 0;
+    }
+    struct record_buf *buf = start_record(SPAN_EVENT, pid_tgid);
+    if (!buf)
+    {
+        return 1;
+    }
+    buf->header.name_id = *name_id;
+    buf->header.level = level;
+    buf->header.span_id = span_id;
+    return submit_record(buf, read_fields(buf, 0, message_size, message));
+}
+
 SEC("usdt")
-int BPF_USDT(perfspan_enter, u64 span_id, u64 name_size, char *name)
+int BPF_USDT(perfspan_value, u64 name_size, char *name, u64 value)
 {
-    return try_submit_event(ENTER, span_id, name_size, name);
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    if (!match_filters(pid_tgid))
+    {
+        return 0;
+    }
+    // name is a literal, so its address identifies the metric. names that are not known yet are sent
+    // to userspace once, and values are dropped until userspace reads the name from the memory of the process
+    struct metric_key key = {.tgid = pid_tgid >> 32, .name_size = name_size, .name = (u64)name};
+    u8 *metric_id = bpf_map_lookup_elem(&metrics, &key);
+    if (!metric_id)
+    {
+        u8 pending = CALLSITE_PENDING;
+        if (bpf_map_update_elem(&metrics, &key, &pending, BPF_NOEXIST) != 0)
+        {
+            return 0;
+        }
+        struct record_buf *buf = start_record(UNKNOWN_METRIC, pid_tgid);
+        if (!buf)
+        {
+            bpf_map_delete_elem(&metrics, &key);
+            return 1;
+        }
+        buf->header.span_id = key.name;
+        buf->header.value = name_size;
+        if (submit_record(buf, 0) != 0)
+        {
+            bpf_map_delete_elem(&metrics, &key);
+        }
+        return 0;
+    }
+    if (*metric_id >= CALLSITE_PENDING)
+    {
+        return 0;
+    }
+    struct record_buf *buf = start_record(VALUE, pid_tgid);
+    if (!buf)
+    {
+        return 1;
+    }
+    buf->header.name_id = *metric_id;
+    buf->header.value = value;
+    return submit_record(buf, 0);
 }
 
+// callsite is registered once, when the first span is created. it is sent to userspace right away,
+// which reads the names of any size from the memory of the process, so that enter and exit pass only the id
 SEC("usdt")
-int BPF_USDT(perfspan_exit, u64 span_id, u64 name_size, char *name)
+int BPF_USDT(perfspan_register, u64 callsite)
 {
-    return try_submit_event(EXIT, span_id, name_size, name);
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    // entry left by the process that exited with the same pid is not valid anymore
+    struct callsite_key key = {.tgid = pid_tgid >> 32, .callsite = callsite};
+    bpf_map_delete_elem(&callsites, &key);
+    lookup_callsite(pid_tgid, callsite);
+    return 0;
 }
 
 struct event _event = {};
+struct record _record = {};
+struct counter_value _counter_value = {};
+struct stack_key _stack_key = {};
 
 char LICENSE[] SEC("license") = "GPL";
\ No newline at end of file
@@ -1,26 +1,105 @@
 #ifndef __PROFILE_H_
 #define __PROFILE_H_
 
-#ifndef MAX_NAME_SIZE
-#define MAX_NAME_SIZE 128
+#ifndef MAX_EVENTS
+#define MAX_EVENTS 64
 #endif
 
-#ifndef MAX_EVENTS
-#define MAX_EVENTS 4
+#ifndef PERF_MAX_STACK_DEPTH
+#define PERF_MAX_STACK_DEPTH 127
+#endif
+
+#ifndef MAX_FIELDS_SIZE
+#define MAX_FIELDS_SIZE 128
+#endif
+
+#ifndef MAX_PREDICATE_SIZE
+#define MAX_PREDICATE_SIZE 64
+#endif
+
+#ifndef MAX_PREDICATES
+#define MAX_PREDICATES 8
+#endif
+
+#ifndef MAX_COMM_SIZE
+#define MAX_COMM_SIZE 16
+#endif
+
+#ifndef MAX_ACTIVE_DEPTH
+#define MAX_ACTIVE_DEPTH 16
 #endif
 
 const __u8 ENTER = 0;
 const __u8 EXIT = 1;
+// name of the metric is not known yet, span_id is the address of the name and value is its size
+const __u8 UNKNOWN_METRIC = 2;
+// callsite is not known yet, span_id is the callsite id to resolve
+const __u8 UNKNOWN_CALLSITE = 3;
+// tracing event inside of the watched span, fields hold the message of the event
+const __u8 SPAN_EVENT = 4;
+// value of the metric recorded by the application, name_id is the index of the metric
+const __u8 VALUE = 5;
+// values recorded on the open span after it was created, fields hold only the recorded pairs
+const __u8 RECORD = 6;
+// exit of the span that didn't match predicates, the span is closed without being recorded
+const __u8 FILTERED_EXIT = 7;
+// span was linked with follows_from to the span that caused it, value is the id of the cause span
+const __u8 FOLLOWS = 8;
+
+// header of every record in the ring buffer. it is followed by nr_counters counter values and
+// fields_size bytes of fields, so that records are only as large as the counters and fields that are read
+struct record
+{
+    __u8 type;
+    __u8 name_id;
+    __u16 cpu;
+    // level of the tracing event, 1 for error to 5 for trace
+    __u8 level;
+    // name of the cause span, set only for follows events
+    __u8 cause_name_id;
+    __u8 nr_counters;
+    __u8 pad;
+    __u16 fields_size;
+    __u64 span_id;
+    __u64 pid_tgid;
+    __u64 timestamp;
+    __u64 value;
+};
 
-struct event 
+// counter is read together with the time it was enabled and running, they diverge when counters are multiplexed
+struct counter_value
+{
+    __u64 counter;
+    __u64 enabled;
+    __u64 running;
+};
+
+// record as it is used by userspace, with counters and fields that were not sent left zeroed
+struct event
 {
     __u8 type;
     __u8 name_id;
     __u16 cpu;
+    __u8 level;
+    __u8 cause_name_id;
     __u64 span_id;
     __u64 pid_tgid;
     __u64 timestamp;
+    __u64 value;
     __u64 counters[MAX_EVENTS];
+    __u64 enabled[MAX_EVENTS];
+    __u64 running[MAX_EVENTS];
+    // fields of the span as name=value pairs terminated by nul, truncated to MAX_FIELDS_SIZE.
+    // events carry their message
+    __u8 fields[MAX_FIELDS_SIZE];
+};
+
+struct stack_key
+{
+    __u32 tgid;
+    __u8 name_id;
+    __s32 user_stack_id;
+    __s32 kernel_stack_id;
 };
 
 #endif
\ No newline at end of file
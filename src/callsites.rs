@@ -0,0 +1,100 @@
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::fs::FileExt,
+};
+
+use eyre::{Result, WrapErr};
+use plain::Plain;
+
+// max size of the strings read from the process, anything larger is not a valid name
+const MAX_STRING_SIZE: u64 = 4096;
+
+/// Layout of the callsite info leaked by the tracing-perfspan layer, address of the info is the callsite id.
+#[repr(C)]
+#[derive(Default)]
+struct CallsiteInfo {
+    name: u64,
+    name_size: u64,
+    target: u64,
+    target_size: u64,
+    file: u64,
+    file_size: u64,
+    line: u64,
+    watched: u64,
+}
+
+unsafe impl Plain for CallsiteInfo {}
+
+/// Metadata of the span callsite.
+pub struct Callsite {
+    pub name: String,
+    pub target: String,
+    pub file: String,
+    pub line: u32,
+}
+
+impl Callsite {
+    /// Reads callsite from the memory of the process.
+    pub fn read(tgid: u32, id: u64) -> Result<Self> {
+        let path = format!("/proc/{}/mem", tgid);
+        let mem = File::open(&path).wrap_err_with(|| format!("failed to open {}", path))?;
+        let mut info = CallsiteInfo::default();
+        let mut buf = vec![0; std::mem::size_of::<CallsiteInfo>()];
+        mem.read_exact_at(&mut buf, id)
+            .wrap_err_with(|| format!("failed to read callsite {:#x}", id))?;
+        plain::copy_from_bytes(&mut info, &buf)
+            .map_err(|e| eyre::eyre!("failed to parse callsite: {:?}", e))?;
+        Ok(Self {
+            name: read_string(&mem, info.name, info.name_size)?,
+            target: read_string(&mem, info.target, info.target_size)?,
+            file: read_string(&mem, info.file, info.file_size)?,
+            line: info.line as u32,
+        })
+    }
+
+    /// Index of the watched span, matched by target::name first and then by the name.
+    pub fn name_id(&self, spans: &[String]) -> Option<u8> {
+        let qualified = format!("{}::{}", self.target, self.name);
+        spans
+            .iter()
+            .position(|span| *span == qualified)
+            .or_else(|| spans.iter().position(|span| *span == self.name))
+            .map(|name_id| name_id as u8)
+    }
+
+    pub fn location(&self) -> Option<String> {
+        (!self.file.is_empty()).then(|| format!("{}:{}", self.file, self.line))
+    }
+
+    /// Marks the callsite in the memory of the process, so that the layer formats fields
+    /// only of the watched spans.
+    pub fn set_watched(tgid: u32, id: u64, watched: bool) -> Result<()> {
+        let path = format!("/proc/{}/mem", tgid);
+        let mem = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .wrap_err_with(|| format!("failed to open {}", path))?;
+        let offset = id + std::mem::offset_of!(CallsiteInfo, watched) as u64;
+        mem.write_all_at(&(watched as u64).to_ne_bytes(), offset)
+            .wrap_err_with(|| format!("failed to mark callsite {:#x}", id))
+    }
+}
+
+/// Reads the name of the metric passed by `record!` from the memory of the process.
+pub fn read_name(tgid: u32, addr: u64, size: u64) -> Result<String> {
+    let path = format!("/proc/{}/mem", tgid);
+    let mem = File::open(&path).wrap_err_with(|| format!("failed to open {}", path))?;
+    read_string(&mem, addr, size)
+}
+
+fn read_string(mem: &File, addr: u64, size: u64) -> Result<String> {
+    eyre::ensure!(
+        size <= MAX_STRING_SIZE,
+        "string of {} bytes is too large",
+        size
+    );
+    let mut buf = vec![0; size as usize];
+    mem.read_exact_at(&mut buf, addr)
+        .wrap_err_with(|| format!("failed to read string at {:#x}", addr))?;
+    String::from_utf8(buf).wrap_err("string is not utf8")
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use eyre::{Result, WrapErr};
+use libbpf_rs::{MapCore, MapFlags};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cpus::online_cpus,
+    events::PerfEventGroup,
+    perf::{enable_on_cpus, open_perf_event},
+    pmu::{core_pmu_for_cpu, CorePmu},
+};
+
+/// Perf counters opened on every online cpu and stored in the perf event array, that is read
+/// by bpf when the span enters and exits.
+///
+/// Cpus that come online after counters were opened are picked up by refresh.
+/// Cpus where counters failed to open are skipped until they go offline and online again.
+pub struct Counters {
+    pid: i32,
+    groups: Vec<PerfEventGroup>,
+    core_pmus: Vec<CorePmu>,
+    // size of the cpu dimension in the perf event array
+    nr_cpus: u32,
+    fds_per_cpu: BTreeMap<u32, Vec<OwnedFd>>,
+    failed: BTreeSet<u32>,
+}
+
+impl Counters {
+    pub fn new(
+        pid: i32,
+        groups: Vec<PerfEventGroup>,
+        core_pmus: Vec<CorePmu>,
+        nr_cpus: u32,
+    ) -> Self {
+        Self {
+            pid,
+            groups,
+            core_pmus,
+            nr_cpus,
+            fds_per_cpu: BTreeMap::new(),
+            failed: BTreeSet::new(),
+        }
+    }
+
+    /// Opens counters on all online cpus. Offline cpus are skipped and reported.
+    pub fn open(&mut self, map: &impl MapCore) -> Result<()> {
+        let online = online_cpus()?;
+        let possible = libbpf_rs::num_possible_cpus()? as u32;
+        let offline = (0..possible)
+            .filter(|cpu| !online.contains(cpu))
+            .collect::<Vec<_>>();
+        if !offline.is_empty() {
+            warn!(
+                "cpus {:?} are offline, counters will be opened when they are online",
+                offline
+            );
+        }
+        self.open_on_cpus(map, &online)
+    }
+
+    /// Opens counters on cpus that came online since the last call.
+    pub fn refresh(&mut self, map: &impl MapCore) -> Result<()> {
+        if self.groups.is_empty() {
+            return Ok(());
+        }
+        let online = online_cpus()?;
+        // counters on a cpu that went offline stop counting, they are reopened once it is back
+        let offline = self
+            .fds_per_cpu
+            .keys()
+            .copied()
+            .filter(|cpu| !online.contains(cpu))
+            .collect::<Vec<_>>();
+        for cpu in offline {
+            if let Some(fds) = self.fds_per_cpu.remove(&cpu) {
+                for counter in 0..fds.len() as u32 {
+                    let _ = map.delete(&self.index(counter, cpu).to_ne_bytes());
+                }
+            }
+        }
+        self.failed.retain(|cpu| online.contains(cpu));
+        let new = online
+            .into_iter()
+            .filter(|cpu| !self.fds_per_cpu.contains_key(cpu) && !self.failed.contains(cpu))
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            return Ok(());
+        }
+        info!("opening counters on cpus {:?} that came online", new);
+        self.open_on_cpus(map, &new)
+    }
+
+    // index of the counter of the cpu in the perf event array, consistent with perfspan.bpf.c
+    fn index(&self, counter: u32, cpu: u32) -> u32 {
+        counter * self.nr_cpus + cpu
+    }
+
+    fn open_on_cpus(&mut self, map: &impl MapCore, cpus: &[u32]) -> Result<()> {
+        if self.groups.is_empty() {
+            return Ok(());
+        }
+        // cpus are marked as failed until their counters are stored in the map
+        self.failed.extend(cpus);
+        let pfds_per_cpu = enable_on_cpus(cpus, |cpu| {
+            let mut pfds_per_group = vec![];
+            let pmu_type = core_pmu_for_cpu(&self.core_pmus, cpu).map(|pmu| pmu.type_);
+            for group in self.groups.iter() {
+                let mut pfds: Vec<OwnedFd> = vec![];
+                for event in group.events.iter() {
+                    let leader = pfds.first().map_or(-1, |leader| leader.as_raw_fd() as i64);
+                    let pfd = open_perf_event(
+                        self.pid,
+                        cpu as i32,
+                        leader,
+                        event.type_,
+                        event.config_for_pmu(pmu_type),
+                    )
+                    .wrap_err_with(|| format!("failed to open perf event {}", event.name))?;
+                    // SAFETY: fd was just opened and is owned only here
+                    pfds.push(unsafe { OwnedFd::from_raw_fd(pfd as i32) });
+                }
+                pfds_per_group.push(pfds);
+            }
+            Ok(pfds_per_group)
+        });
+        for (cpu, pfds_per_group) in pfds_per_cpu {
+            let pfds = pfds_per_group.into_iter().flatten().collect::<Vec<_>>();
+            if let Err(err) = self.store(map, cpu, &pfds) {
+                warn!("skipping cpu {}: {:?}", cpu, err);
+                continue;
+            }
+            self.failed.remove(&cpu);
+            self.fds_per_cpu.insert(cpu, pfds);
+        }
+        Ok(())
+    }
+
+    fn store(&self, map: &impl MapCore, cpu: u32, pfds: &[OwnedFd]) -> Result<()> {
+        // counter is the index of the counter in the event, it is assigned sequentially across groups
+        for (counter, pfd) in pfds.iter().enumerate() {
+            debug!("opened perf event: {} on cpu {}", pfd.as_raw_fd(), cpu);
+            let index = self.index(counter as u32, cpu).to_ne_bytes();
+            if let Err(err) = map.update(
+                &index,
+                &(pfd.as_raw_fd() as u32).to_ne_bytes(),
+                MapFlags::ANY,
+            ) {
+                // counters that were already stored are removed, otherwise bpf reads closed events
+                for stored in 0..counter as u32 {
+                    let _ = map.delete(&self.index(stored, cpu).to_ne_bytes());
+                }
+                return Err(err).wrap_err("failed to store perf event");
+            }
+        }
+        Ok(())
+    }
+}
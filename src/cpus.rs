@@ -0,0 +1,53 @@
+use std::fs;
+
+use eyre::{Result, WrapErr};
+
+const SYSFS_ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// Returns cpus that are currently online.
+pub fn online_cpus() -> Result<Vec<u32>> {
+    let online = fs::read_to_string(SYSFS_ONLINE_CPUS)
+        .wrap_err_with(|| format!("failed to read {}", SYSFS_ONLINE_CPUS))?;
+    parse_cpu_list(&online)
+}
+
+/// Parses cpu list in the format used by sysfs, e.g. "0-3,5,7-8".
+pub fn parse_cpu_list(s: &str) -> Result<Vec<u32>> {
+    let parse_cpu = |cpu: &str| {
+        cpu.parse::<u32>()
+            .wrap_err_with(|| format!("invalid cpu list: {}", s))
+    };
+    let mut cpus = vec![];
+    for part in s.trim().split(',').filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => cpus.extend(parse_cpu(start)?..=parse_cpu(end)?),
+            None => cpus.push(parse_cpu(part)?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges_and_single_cpus() {
+        assert_eq!(parse_cpu_list("0-3,8").unwrap(), [0, 1, 2, 3, 8]);
+        assert_eq!(parse_cpu_list("0,2-3,5-6\n").unwrap(), [0, 2, 3, 5, 6]);
+        assert_eq!(parse_cpu_list("7").unwrap(), [7]);
+    }
+
+    #[test]
+    fn parses_empty_list() {
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_list() {
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("0-").is_err());
+        assert!(parse_cpu_list("0-3,x").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::{borrow::Cow, fmt::Display, str::FromStr};
+
+use clap::builder::{IntoResettable, Resettable, StyledStr};
+use eyre::Result;
+use libbpf_rs::libbpf_sys::{
+    self, PERF_COUNT_HW_CACHE_DTLB as DTLB, PERF_COUNT_HW_CACHE_ITLB as ITLB,
+    PERF_COUNT_HW_CACHE_L1D as L1D, PERF_COUNT_HW_CACHE_L1I as L1I, PERF_COUNT_HW_CACHE_LL as LL,
+    PERF_COUNT_HW_CACHE_NODE as NODE, PERF_COUNT_HW_CACHE_OP_READ as READ,
+    PERF_COUNT_HW_CACHE_OP_WRITE as WRITE, PERF_COUNT_HW_CACHE_RESULT_ACCESS as ACCESS,
+    PERF_COUNT_HW_CACHE_RESULT_MISS as MISS,
+};
+
+use tracing::warn;
+
+use crate::pmu;
+
+// hardware and cache events can be bound to the pmu by setting its type in the upper bits of config
+const PERF_PMU_TYPE_SHIFT: u32 = 32;
+
+pub struct PerfEventSpecHelp {}
+
+impl IntoResettable<StyledStr> for PerfEventSpecHelp {
+    fn into_resettable(self) -> Resettable<StyledStr> {
+        // build help string from SUPPORTED_PERF_EVENTS
+        let mut help = String::new();
+        help.push_str("supported events:\n");
+        for event in SUPPORTED_PERF_EVENTS {
+            help.push_str(&format!(" - {}\n", &event));
+        }
+        help.push_str(" - r<hex> raw pmu event, e.g. r01c2\n");
+        help.push_str(" - pmu/term=value,.../ event encoded using sysfs format, e.g. cpu/event=0x3c,umask=0x00/\n");
+        help.push_str("sample period after = is accepted for compatibility and ignored, counters are read when spans enter and exit.\n");
+        help.push_str(
+            "events separated by comma are scheduled as a group, e.g. cycles,instructions",
+        );
+        Resettable::Value(StyledStr::from(help))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PerfEventSpec {
+    pub name: Cow<'static, str>,
+    pub type_: u32,
+    pub config: u64,
+}
+
+impl PerfEventSpec {
+    /// Returns config of the event for the core pmu.
+    /// On hybrid cpus generic hardware and cache events must be opened for each core type,
+    /// otherwise they count only on one of them.
+    pub fn config_for_pmu(&self, pmu_type: Option<u32>) -> u64 {
+        match pmu_type {
+            Some(pmu_type)
+                if self.type_ == libbpf_sys::PERF_TYPE_HARDWARE
+                    || self.type_ == libbpf_sys::PERF_TYPE_HW_CACHE =>
+            {
+                self.config | ((pmu_type as u64) << PERF_PMU_TYPE_SHIFT)
+            }
+            _ => self.config,
+        }
+    }
+}
+
+impl FromStr for PerfEventSpec {
+    type Err = eyre::Error;
+    /// Parses event spec from a string into one of the supported events.
+    /// The format is "name=period" where period is optional and name must match of the existing events,
+    /// be a raw event in the form of "r<hex>" or a pmu event in the form of "pmu/term=value,.../".
+    ///
+    /// Counters are read when the span enters and exits, so the period is accepted only
+    /// for compatibility and is ignored.
+    fn from_str(s: &str) -> Result<Self> {
+        let name = strip_period(s)?;
+        eyre::ensure!(!name.is_empty(), "missing event name");
+        if let Some(matched) = SUPPORTED_PERF_EVENTS.iter().find(|e| e.name == name) {
+            Ok(matched.clone())
+        } else if name.contains('/') {
+            let (type_, config) = pmu::parse_event(name)?;
+            Ok(PerfEventSpec {
+                name: Cow::Owned(name.to_string()),
+                type_,
+                config,
+            })
+        } else if let Some(config) = name
+            .strip_prefix('r')
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        {
+            Ok(PerfEventSpec {
+                name: Cow::Owned(name.to_string()),
+                type_: libbpf_sys::PERF_TYPE_RAW,
+                config,
+            })
+        } else {
+            eyre::bail!("unknown event name: {}", name);
+        }
+    }
+}
+
+fn strip_period(s: &str) -> Result<&str> {
+    // pmu events use "=" inside the slashes, therefore period is looked up after the closing slash
+    let (name, period) = match s.rfind('/') {
+        Some(end) => {
+            let (name, rest) = s.split_at(end + 1);
+            let period = rest.strip_prefix('=');
+            eyre::ensure!(
+                rest.is_empty() || period.is_some(),
+                "unexpected suffix after pmu event: {}",
+                rest
+            );
+            (name, period)
+        }
+        None => match s.split_once('=') {
+            Some((name, period)) => (name, Some(period)),
+            None => (s, None),
+        },
+    };
+    if let Some(period) = period {
+        period
+            .parse::<u64>()
+            .map_err(|e| eyre::eyre!("invalid period {} of event {}: {}", period, name, e))?;
+        warn!(
+            "period of event {} is ignored, counters are read when spans enter and exit",
+            name
+        );
+    }
+    Ok(name)
+}
+
+/// Events that are always scheduled together on the pmu.
+///
+/// When there are more groups than the pmu can hold, kernel multiplexes them and
+/// counters are scaled by the time they were running.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PerfEventGroup {
+    pub events: Vec<PerfEventSpec>,
+}
+
+impl FromStr for PerfEventGroup {
+    type Err = eyre::Error;
+    /// Parses comma separated list of events.
+    fn from_str(s: &str) -> Result<Self> {
+        let events = split_events(s)
+            .into_iter()
+            .map(str::parse)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { events })
+    }
+}
+
+// commas inside the pmu events, such as cpu/event=0x3c,umask=0x00/, don't separate events
+fn split_events(s: &str) -> Vec<&str> {
+    let mut events = vec![];
+    let mut slashes = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '/' => slashes += 1,
+            ',' if slashes % 2 == 0 => {
+                events.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    events.push(&s[start..]);
+    events
+}
+
+impl Display for PerfEventSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+const fn hw_cache(id: u32, op: u32, result: u32) -> u64 {
+    (id as u64) | ((op as u64) << 8) | ((result as u64) << 16)
+}
+
+const fn hardware(name: &'static str, config: u32) -> PerfEventSpec {
+    PerfEventSpec {
+        name: Cow::Borrowed(name),
+        type_: libbpf_sys::PERF_TYPE_HARDWARE,
+        config: config as u64,
+    }
+}
+
+const fn software(name: &'static str, config: u32) -> PerfEventSpec {
+    PerfEventSpec {
+        name: Cow::Borrowed(name),
+        type_: libbpf_sys::PERF_TYPE_SOFTWARE,
+        config: config as u64,
+    }
+}
+
+const fn cache(name: &'static str, id: u32, op: u32, result: u32) -> PerfEventSpec {
+    PerfEventSpec {
+        name: Cow::Borrowed(name),
+        type_: libbpf_sys::PERF_TYPE_HW_CACHE,
+        config: hw_cache(id, op, result),
+    }
+}
+
+pub const SUPPORTED_PERF_EVENTS: &[PerfEventSpec] = &[
+    hardware("cycles", libbpf_sys::PERF_COUNT_HW_CPU_CYCLES),
+    hardware("instructions", libbpf_sys::PERF_COUNT_HW_INSTRUCTIONS),
+    hardware(
+        "cache_references",
+        libbpf_sys::PERF_COUNT_HW_CACHE_REFERENCES,
+    ),
+    hardware("cache_misses", libbpf_sys::PERF_COUNT_HW_CACHE_MISSES),
+    hardware(
+        "branch_instructions",
+        libbpf_sys::PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+    ),
+    hardware("branch_misses", libbpf_sys::PERF_COUNT_HW_BRANCH_MISSES),
+    hardware("bus_cycles", libbpf_sys::PERF_COUNT_HW_BUS_CYCLES),
+    hardware(
+        "stalled_cycles_frontend",
+        libbpf_sys::PERF_COUNT_HW_STALLED_CYCLES_FRONTEND,
+    ),
+    hardware(
+        "stalled_cycles_backend",
+        libbpf_sys::PERF_COUNT_HW_STALLED_CYCLES_BACKEND,
+    ),
+    hardware("ref_cpu_cycles", libbpf_sys::PERF_COUNT_HW_REF_CPU_CYCLES),
+    // clocks are counted in nanoseconds
+    software("cpu_clock", libbpf_sys::PERF_COUNT_SW_CPU_CLOCK),
+    software("task_clock", libbpf_sys::PERF_COUNT_SW_TASK_CLOCK),
+    software("page_faults", libbpf_sys::PERF_COUNT_SW_PAGE_FAULTS),
+    software("minor_faults", libbpf_sys::PERF_COUNT_SW_PAGE_FAULTS_MIN),
+    software("major_faults", libbpf_sys::PERF_COUNT_SW_PAGE_FAULTS_MAJ),
+    software(
+        "context_switches",
+        libbpf_sys::PERF_COUNT_SW_CONTEXT_SWITCHES,
+    ),
+    software("cpu_migrations", libbpf_sys::PERF_COUNT_SW_CPU_MIGRATIONS),
+    // names follow perf list, so that they are familiar
+    cache("L1-dcache-loads", L1D, READ, ACCESS),
+    cache("L1-dcache-load-misses", L1D, READ, MISS),
+    cache("L1-dcache-stores", L1D, WRITE, ACCESS),
+    cache("L1-dcache-store-misses", L1D, WRITE, MISS),
+    cache("L1-icache-load-misses", L1I, READ, MISS),
+    cache("LLC-loads", LL, READ, ACCESS),
+    cache("LLC-load-misses", LL, READ, MISS),
+    cache("LLC-stores", LL, WRITE, ACCESS),
+    cache("LLC-store-misses", LL, WRITE, MISS),
+    cache("dTLB-loads", DTLB, READ, ACCESS),
+    cache("dTLB-load-misses", DTLB, READ, MISS),
+    cache("dTLB-stores", DTLB, WRITE, ACCESS),
+    cache("dTLB-store-misses", DTLB, WRITE, MISS),
+    cache("iTLB-loads", ITLB, READ, ACCESS),
+    cache("iTLB-load-misses", ITLB, READ, MISS),
+    cache("node-loads", NODE, READ, ACCESS),
+    cache("node-load-misses", NODE, READ, MISS),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_group_outside_of_pmu_events() {
+        assert_eq!(
+            split_events("cycles,cpu/event=0x3c,umask=0x00/,instructions"),
+            ["cycles", "cpu/event=0x3c,umask=0x00/", "instructions"]
+        );
+        assert_eq!(
+            split_events("cpu/event=0x3c/,cpu/event=0xc4,umask=0x20/"),
+            ["cpu/event=0x3c/", "cpu/event=0xc4,umask=0x20/"]
+        );
+        assert_eq!(split_events("cycles"), ["cycles"]);
+    }
+
+    #[test]
+    fn parses_group_of_known_and_raw_events() {
+        let group = "cycles,r01c2,LLC-load-misses"
+            .parse::<PerfEventGroup>()
+            .unwrap();
+        let names = group
+            .events
+            .iter()
+            .map(|event| event.name.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["cycles", "r01c2", "LLC-load-misses"]);
+        assert_eq!(group.events[1].type_, libbpf_sys::PERF_TYPE_RAW);
+        assert_eq!(group.events[1].config, 0x01c2);
+        assert_eq!(group.events[2].config, hw_cache(LL, READ, MISS));
+    }
+
+    #[test]
+    fn ignores_sample_period() {
+        let cycles = "cycles=1000".parse::<PerfEventSpec>().unwrap();
+        assert_eq!(cycles.name, "cycles");
+        let raw = "r01c2=100".parse::<PerfEventSpec>().unwrap();
+        assert_eq!(raw.config, 0x01c2);
+        assert!("cycles=fast".parse::<PerfEventSpec>().is_err());
+        assert!("=1000".parse::<PerfEventSpec>().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_and_empty_events() {
+        assert!("cycles,unknown".parse::<PerfEventGroup>().is_err());
+        assert!("cycles,".parse::<PerfEventGroup>().is_err());
+        assert!("rxyz".parse::<PerfEventSpec>().is_err());
+    }
+
+    #[test]
+    fn binds_hardware_events_to_core_pmu() {
+        let cycles = "cycles".parse::<PerfEventSpec>().unwrap();
+        assert_eq!(cycles.config_for_pmu(None), cycles.config);
+        assert_eq!(cycles.config_for_pmu(Some(8)), cycles.config | 8 << 32);
+        let misses = "LLC-load-misses".parse::<PerfEventSpec>().unwrap();
+        assert_eq!(misses.config_for_pmu(Some(10)), misses.config | 10 << 32);
+        // software and raw events are not bound to the core type
+        let faults = "page_faults".parse::<PerfEventSpec>().unwrap();
+        assert_eq!(faults.config_for_pmu(Some(8)), faults.config);
+        let raw = "r01c2".parse::<PerfEventSpec>().unwrap();
+        assert_eq!(raw.config_for_pmu(Some(8)), raw.config);
+    }
+}
@@ -0,0 +1,303 @@
+use std::str::FromStr;
+
+use hashbrown::{HashMap, HashSet};
+
+// this value should be consistent with value set in perfspan.h
+pub const MAX_PREDICATE_SIZE: usize = 64;
+pub const MAX_PREDICATES: usize = 8;
+
+/// Predicate in the form of `name=value`, span is submitted only if its fields have the same pair.
+///
+/// Value is compared as it was formatted by the layer, strings recorded with Debug are quoted.
+#[derive(Debug, Clone)]
+pub struct FieldPredicate(pub String);
+
+impl FromStr for FieldPredicate {
+    type Err = eyre::Error;
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let Some((name, _)) = s.split_once('=') else {
+            eyre::bail!("predicate should be in the form of name=value: {}", s);
+        };
+        eyre::ensure!(!name.is_empty(), "predicate without field name: {}", s);
+        eyre::ensure!(
+            s.len() <= MAX_PREDICATE_SIZE,
+            "predicate {} is longer than {} bytes",
+            s,
+            MAX_PREDICATE_SIZE
+        );
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl FieldPredicate {
+    /// Key in the bpf map, padded with zeroes.
+    pub fn key(&self) -> [u8; MAX_PREDICATE_SIZE] {
+        let mut key = [0; MAX_PREDICATE_SIZE];
+        key[..self.0.len()].copy_from_slice(self.0.as_bytes());
+        key
+    }
+}
+
+/// Returns value of the field from the fields passed by the probe.
+///
+/// Fields are `name=value` pairs terminated by nul byte, the last pair may be truncated by bpf.
+pub fn field_value<'a>(fields: &'a [u8], name: &str) -> Option<&'a str> {
+    fields
+        .split(|b| *b == 0)
+        .filter_map(|pair| std::str::from_utf8(pair).ok())
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(field, _)| *field == name)
+        .map(|(_, value)| value)
+}
+
+/// Merges values recorded on the span into its fields, recorded values replace the ones with the same name.
+pub fn merge_fields(fields: &[u8], recorded: &[u8]) -> Vec<u8> {
+    let names = pairs(recorded).map(field_name).collect::<Vec<_>>();
+    let mut merged = Vec::with_capacity(fields.len() + recorded.len());
+    let kept = pairs(fields).filter(|pair| !names.contains(&field_name(pair)));
+    for pair in kept.chain(pairs(recorded)) {
+        merged.extend_from_slice(pair);
+        merged.push(0);
+    }
+    merged
+}
+
+fn pairs(fields: &[u8]) -> impl Iterator<Item = &[u8]> {
+    fields.split(|b| *b == 0).filter(|pair| !pair.is_empty())
+}
+
+fn field_name(pair: &[u8]) -> &[u8] {
+    pair.split(|b| *b == b'=').next().unwrap_or(pair)
+}
+
+/// Splits histograms of every span by the value of the field.
+///
+/// Only the first `limit` values of every span get their own histograms, the rest of the values
+/// and spans without the field are recorded into the `other` group.
+pub struct GroupBy {
+    field: String,
+    limit: usize,
+    values: HashMap<u8, HashSet<String>>,
+}
+
+impl GroupBy {
+    pub fn new(field: String, limit: usize) -> Self {
+        Self {
+            field,
+            limit,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn label(&mut self, name_id: u8, fields: &[u8]) -> String {
+        let values = self.values.entry(name_id).or_default();
+        let value = match field_value(fields, &self.field) {
+            Some(value) if values.contains(value) => value,
+            Some(value) if values.len() < self.limit => {
+                values.insert(value.to_string());
+                value
+            }
+            _ => "other",
+        };
+        format!("{}={}", self.field, value)
+    }
+}
+
+/// Field that marks the span as an error, given as `name`, `name=value` or `name>=number`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorField {
+    /// Error unless the value means success.
+    Set(String),
+    /// Error if the value is the same, for example `status=failed`.
+    Equals(String, String),
+    /// Error if the value is a number that is at least the threshold, for example `status>=500`.
+    AtLeast(String, i64),
+}
+
+impl FromStr for ErrorField {
+    type Err = eyre::Error;
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let field = if let Some((name, threshold)) = s.split_once(">=") {
+            let threshold = threshold
+                .parse()
+                .map_err(|_| eyre::eyre!("threshold of the error field is not a number: {}", s))?;
+            Self::AtLeast(name.to_string(), threshold)
+        } else if let Some((name, value)) = s.split_once('=') {
+            Self::Equals(name.to_string(), value.to_string())
+        } else {
+            Self::Set(s.to_string())
+        };
+        eyre::ensure!(!field.name().is_empty(), "error field without name: {}", s);
+        Ok(field)
+    }
+}
+
+impl ErrorField {
+    fn name(&self) -> &str {
+        match self {
+            Self::Set(name) | Self::Equals(name, _) | Self::AtLeast(name, _) => name,
+        }
+    }
+
+    fn is_error(&self, value: &str) -> bool {
+        let value = value.trim_matches('"');
+        match self {
+            Self::Set(_) => is_error_value(value),
+            Self::Equals(_, error) => value == error,
+            Self::AtLeast(_, threshold) => {
+                value.parse().is_ok_and(|value: i64| value >= *threshold)
+            }
+        }
+    }
+}
+
+/// Classifies completed spans as ok or error.
+///
+/// Span is an error if it emitted an ERROR event, or if any of the error fields is set to a value other than
+/// the ones that mean success, so that both `error = %err` and `otel.status_code = "ERROR"` are errors.
+pub struct Outcome {
+    fields: Vec<ErrorField>,
+}
+
+// fields that are conventionally used for the outcome of the span, checked in addition to the configured ones
+const ERROR_FIELDS: &[&str] = &["error", "otel.status_code"];
+
+impl Outcome {
+    pub fn new(mut fields: Vec<ErrorField>) -> Self {
+        fields.extend(
+            ERROR_FIELDS
+                .iter()
+                .map(|field| ErrorField::Set(field.to_string())),
+        );
+        Self { fields }
+    }
+
+    pub fn label(&self, fields: &[u8], error_event: bool) -> &'static str {
+        let error_field = self.fields.iter().any(|error| {
+            field_value(fields, error.name()).is_some_and(|value| error.is_error(value))
+        });
+        if error_event || error_field {
+            "outcome=error"
+        } else {
+            "outcome=ok"
+        }
+    }
+}
+
+fn is_error_value(value: &str) -> bool {
+    !["", "false", "0", "ok", "unset"]
+        .iter()
+        .any(|ok| value.eq_ignore_ascii_case(ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_predicate_up_to_max_size() {
+        let predicate = format!("name={}", "v".repeat(MAX_PREDICATE_SIZE - 5));
+        let key = predicate.parse::<FieldPredicate>().unwrap().key();
+        assert_eq!(&key[..], predicate.as_bytes());
+
+        let predicate = format!("name={}", "v".repeat(MAX_PREDICATE_SIZE - 4));
+        assert!(predicate.parse::<FieldPredicate>().is_err());
+    }
+
+    #[test]
+    fn pads_predicate_key_with_zeroes() {
+        let key = "method=GET".parse::<FieldPredicate>().unwrap().key();
+        assert_eq!(&key[..10], b"method=GET");
+        assert!(key[10..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn rejects_predicate_without_name_or_value() {
+        assert!("method".parse::<FieldPredicate>().is_err());
+        assert!("=GET".parse::<FieldPredicate>().is_err());
+        // empty value matches the field recorded as empty string
+        assert!("method=".parse::<FieldPredicate>().is_ok());
+    }
+
+    #[test]
+    fn finds_field_value() {
+        let fields = b"method=GET\0path=\"/users\"\0\0\0";
+        assert_eq!(field_value(fields, "method"), Some("GET"));
+        assert_eq!(field_value(fields, "path"), Some("\"/users\""));
+        assert_eq!(field_value(fields, "status"), None);
+        // pair truncated by bpf
+        assert_eq!(field_value(b"method=GE", "method"), Some("GE"));
+    }
+
+    #[test]
+    fn replaces_recorded_fields() {
+        let merged = merge_fields(b"method=GET\0status=0\0\0\0", b"status=200\0bytes=10\0");
+        assert_eq!(merged, b"method=GET\0status=200\0bytes=10\0");
+        assert_eq!(merge_fields(b"method=GET\0", b""), b"method=GET\0");
+        assert_eq!(merge_fields(b"", b"status=200\0"), b"status=200\0");
+    }
+
+    #[test]
+    fn groups_values_over_limit_into_other() {
+        let mut group_by = GroupBy::new("method".to_string(), 2);
+        assert_eq!(group_by.label(0, b"method=GET\0"), "method=GET");
+        assert_eq!(group_by.label(0, b"method=PUT\0"), "method=PUT");
+        assert_eq!(group_by.label(0, b"method=POST\0"), "method=other");
+        assert_eq!(group_by.label(0, b"method=GET\0"), "method=GET");
+        assert_eq!(group_by.label(0, b"path=/\0"), "method=other");
+        // limit is applied to every span separately
+        assert_eq!(group_by.label(1, b"method=POST\0"), "method=POST");
+    }
+
+    #[test]
+    fn classifies_outcome_by_error_fields() {
+        let outcome = Outcome::new(vec!["status".parse().unwrap()]);
+        assert_eq!(outcome.label(b"method=GET\0", false), "outcome=ok");
+        assert_eq!(outcome.label(b"error=false\0", false), "outcome=ok");
+        assert_eq!(
+            outcome.label(b"otel.status_code=\"OK\"\0", false),
+            "outcome=ok"
+        );
+        assert_eq!(outcome.label(b"status=0\0", false), "outcome=ok");
+        assert_eq!(outcome.label(b"error=timeout\0", false), "outcome=error");
+        assert_eq!(
+            outcome.label(b"otel.status_code=\"ERROR\"\0", false),
+            "outcome=error"
+        );
+        assert_eq!(outcome.label(b"status=503\0", false), "outcome=error");
+    }
+
+    #[test]
+    fn error_event_fails_span() {
+        let outcome = Outcome::new(vec![]);
+        assert_eq!(outcome.label(b"error=false\0", true), "outcome=error");
+    }
+
+    #[test]
+    fn classifies_outcome_by_error_field_value() {
+        let outcome = Outcome::new(vec!["status>=500".parse().unwrap()]);
+        assert_eq!(outcome.label(b"status=200\0", false), "outcome=ok");
+        assert_eq!(outcome.label(b"status=404\0", false), "outcome=ok");
+        assert_eq!(outcome.label(b"status=503\0", false), "outcome=error");
+        assert_eq!(outcome.label(b"status=\"503\"\0", false), "outcome=error");
+        assert_eq!(outcome.label(b"status=unknown\0", false), "outcome=ok");
+
+        let outcome = Outcome::new(vec!["result=failed".parse().unwrap()]);
+        assert_eq!(outcome.label(b"result=done\0", false), "outcome=ok");
+        assert_eq!(outcome.label(b"result=failed\0", false), "outcome=error");
+    }
+
+    #[test]
+    fn parses_error_fields() {
+        assert_eq!(
+            "status".parse::<ErrorField>().unwrap(),
+            ErrorField::Set("status".to_string())
+        );
+        assert_eq!(
+            "status>=500".parse::<ErrorField>().unwrap(),
+            ErrorField::AtLeast("status".to_string(), 500)
+        );
+        assert!("status>=5xx".parse::<ErrorField>().is_err());
+        assert!("=failed".parse::<ErrorField>().is_err());
+    }
+}
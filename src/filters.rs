@@ -0,0 +1,187 @@
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+
+// this value should be consistent with value set in perfspan.h
+const MAX_COMM_SIZE: usize = 16;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Name of the thread, matched as a prefix if it ends with `*`.
+#[derive(Debug, Clone)]
+pub struct CommPattern {
+    comm: String,
+    prefix: bool,
+}
+
+impl FromStr for CommPattern {
+    type Err = eyre::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (comm, prefix) = match s.strip_suffix('*') {
+            Some(comm) => (comm, true),
+            None => (s, false),
+        };
+        // kernel truncates names to 15 bytes, the last one is nul
+        eyre::ensure!(
+            comm.len() < MAX_COMM_SIZE,
+            "thread name {} is longer than {} bytes",
+            comm,
+            MAX_COMM_SIZE - 1
+        );
+        Ok(Self {
+            comm: comm.to_string(),
+            prefix,
+        })
+    }
+}
+
+impl CommPattern {
+    /// Key in the lpm trie: prefix length in bits followed by the name.
+    /// Exact names include nul byte into the prefix, so that longer names don't match.
+    pub fn key(&self) -> [u8; 4 + MAX_COMM_SIZE] {
+        let size = if self.prefix {
+            self.comm.len()
+        } else {
+            self.comm.len() + 1
+        };
+        let mut key = [0; 4 + MAX_COMM_SIZE];
+        key[..4].copy_from_slice(&(size as u32 * 8).to_ne_bytes());
+        key[4..4 + self.comm.len()].copy_from_slice(self.comm.as_bytes());
+        key
+    }
+}
+
+/// Returns id of the cgroup v2, paths are relative to /sys/fs/cgroup unless absolute.
+///
+/// Id of the cgroup is the inode of its directory.
+pub fn cgroup_id(path: &Path) -> Result<u64> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        PathBuf::from(CGROUP_ROOT).join(path)
+    };
+    let metadata = path
+        .metadata()
+        .wrap_err_with(|| format!("failed to read cgroup {}", path.display()))?;
+    eyre::ensure!(
+        metadata.is_dir(),
+        "cgroup {} is not a directory",
+        path.display()
+    );
+    Ok(metadata.ino())
+}
+
+/// Threads, thread names and cgroups to monitor, they can be updated while perfspan runs.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    pub tids: Vec<u32>,
+    pub comms: Vec<CommPattern>,
+    pub cgroups: Vec<PathBuf>,
+}
+
+impl FromStr for Filters {
+    type Err = eyre::Error;
+    /// Parses one filter per line, such as `tid 12`, `comm worker-*` or `cgroup system.slice/nginx.service`.
+    /// Empty lines and lines starting with `#` are skipped.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut filters = Self::default();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((kind, value)) = line.split_once(char::is_whitespace) else {
+                eyre::bail!("filter should be in the form of kind value: {}", line);
+            };
+            let value = value.trim();
+            match kind {
+                "tid" => filters.tids.push(
+                    value
+                        .parse()
+                        .wrap_err_with(|| format!("invalid thread id {}", value))?,
+                ),
+                "comm" => filters.comms.push(value.parse()?),
+                "cgroup" => filters.cgroups.push(PathBuf::from(value)),
+                _ => eyre::bail!("unknown filter {}, expected tid, comm or cgroup", kind),
+            }
+        }
+        Ok(filters)
+    }
+}
+
+/// File with filters, it is read again when it is modified.
+pub struct FilterFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl FilterFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns filters from the file if it was modified since it was read last time.
+    pub fn reload(&mut self) -> Result<Option<Filters>> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .wrap_err_with(|| format!("failed to read {}", self.path.display()))?;
+        if self.modified == Some(modified) {
+            return Ok(None);
+        }
+        // file with errors is not parsed again until it is modified
+        self.modified = Some(modified);
+        let filters = fs::read_to_string(&self.path)
+            .wrap_err_with(|| format!("failed to read {}", self.path.display()))?
+            .parse()?;
+        Ok(Some(filters))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filters() {
+        let filters = "# workers of the service\ntid 12\n\ncomm worker-*\ncgroup system.slice/nginx.service\n"
+            .parse::<Filters>()
+            .unwrap();
+        assert_eq!(filters.tids, [12]);
+        assert_eq!(filters.comms.len(), 1);
+        assert_eq!(
+            filters.cgroups,
+            [PathBuf::from("system.slice/nginx.service")]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!("tid".parse::<Filters>().is_err());
+        assert!("tid twelve".parse::<Filters>().is_err());
+        assert!("pid 12".parse::<Filters>().is_err());
+        assert!("comm thread-name-too-long".parse::<Filters>().is_err());
+    }
+
+    #[test]
+    fn reloads_modified_file() {
+        let path = std::env::temp_dir().join(format!("perfspan-filters-{}", std::process::id()));
+        fs::write(&path, "tid 12\n").unwrap();
+        let mut file = FilterFile::new(path.clone());
+        assert_eq!(file.reload().unwrap().unwrap().tids, [12]);
+        assert!(file.reload().unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+        assert!(file.reload().is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use hashbrown::HashMap;
+use tracing::debug;
+
+use crate::{spans::CompletedSpan, Event};
+
+/// Matches spans linked with follows_from to the spans that caused them, such as a background task
+/// and the request that spawned it.
+///
+/// Latency is measured from the last enter of the cause before the link, to the first enter and exit
+/// of the linked span. Spans that are entered several times, like futures, are measured until the first exit.
+///
+/// Links made after the cause exited are ignored. Once MAX_PENDING linked spans are waiting for their exit,
+/// links to causes entered more than PENDING_TIMEOUT ago are dropped.
+#[derive(Default)]
+pub struct Flows {
+    // enter of every watched span that is open, by tgid and span id
+    enters: HashMap<(u32, u64), u64>,
+    // linked spans that haven't exited yet, with the name of the cause and its enter
+    pending: HashMap<(u32, u64), (u8, u64)>,
+}
+
+const MAX_PENDING: usize = 1 << 16;
+const PENDING_TIMEOUT: u64 = 60_000_000_000;
+
+/// Latency from the enter of the cause span to the enter and exit of the span that follows from it.
+pub struct Flow {
+    pub cause: u8,
+    pub effect: u8,
+    pub to_enter: u64,
+    pub to_exit: u64,
+}
+
+impl Flows {
+    pub fn enter(&mut self, ev: &Event) {
+        self.enters
+            .insert(process_key(ev.pid_tgid, ev.span_id), ev.timestamp);
+    }
+
+    /// Forgets the enter and the link of the span, it is called after every exit whether the span
+    /// is recorded or not, so that only the first exit of the linked span is measured.
+    pub fn exit(&mut self, ev: &Event) {
+        let key = process_key(ev.pid_tgid, ev.span_id);
+        self.enters.remove(&key);
+        self.pending.remove(&key);
+    }
+
+    /// Links the span with the cause, links to causes that are not open are ignored.
+    pub fn link(&mut self, ev: &Event) {
+        let Some(enter) = self
+            .enters
+            .get(&process_key(ev.pid_tgid, ev.value))
+            .copied()
+        else {
+            return;
+        };
+        if self.pending.len() >= MAX_PENDING {
+            self.pending
+                .retain(|_, (_, pending)| ev.timestamp.saturating_sub(*pending) < PENDING_TIMEOUT);
+            if self.pending.len() >= MAX_PENDING {
+                debug!(
+                    "dropping link of span {}, too many spans are waiting for exit",
+                    ev.span_id
+                );
+                return;
+            }
+        }
+        self.pending.insert(
+            process_key(ev.pid_tgid, ev.span_id),
+            (ev.cause_name_id, enter),
+        );
+    }
+
+    /// Returns the flow if the completed span was linked to the cause.
+    pub fn complete(&mut self, span: &CompletedSpan) -> Option<Flow> {
+        let key = process_key(span.exit.pid_tgid, span.exit.span_id);
+        let (cause, enter) = self.pending.remove(&key)?;
+        Some(Flow {
+            cause,
+            effect: span.exit.name_id,
+            to_enter: span.enter.timestamp.saturating_sub(enter),
+            to_exit: span.exit.timestamp.saturating_sub(enter),
+        })
+    }
+}
+
+fn process_key(pid_tgid: u64, span_id: u64) -> (u32, u64) {
+    ((pid_tgid >> 32) as u32, span_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        spans::SpanTracker,
+        testing::{event, PID_TGID},
+    };
+
+    fn link(effect: u64, cause: u64, cause_name_id: u8, timestamp: u64) -> Event {
+        Event {
+            value: cause,
+            cause_name_id,
+            ..event(8, 0, effect, timestamp)
+        }
+    }
+
+    // feeds events to the tracker and flows in the same order as poll_events
+    fn replay(events: &[Event]) -> Vec<Flow> {
+        let mut tracker = SpanTracker::default();
+        let mut flows = Flows::default();
+        let mut completed = vec![];
+        for ev in events {
+            match ev.r#type {
+                0 => {
+                    tracker.enter(ev);
+                    flows.enter(ev);
+                }
+                1 => {
+                    if let Some(span) = tracker.exit(ev) {
+                        completed.extend(flows.complete(&span));
+                    }
+                    flows.exit(ev);
+                }
+                8 => flows.link(ev),
+                _ => unreachable!(),
+            }
+        }
+        completed
+    }
+
+    #[test]
+    fn measures_from_enter_of_cause() {
+        let flows = replay(&[
+            event(0, 0, 1, 100),
+            link(2, 1, 0, 110),
+            event(1, 0, 1, 120),
+            event(0, 1, 2, 150),
+            event(1, 1, 2, 190),
+        ]);
+        assert_eq!(flows.len(), 1);
+        assert_eq!((flows[0].cause, flows[0].effect), (0, 1));
+        assert_eq!((flows[0].to_enter, flows[0].to_exit), (50, 90));
+    }
+
+    #[test]
+    fn drops_link_from_exited_span() {
+        let mut flows = Flows::default();
+        flows.enter(&event(0, 0, 1, 100));
+        flows.exit(&event(1, 0, 1, 120));
+        assert!(flows.enters.is_empty());
+        flows.link(&link(2, 1, 0, 130));
+        assert!(flows.pending.is_empty());
+
+        let completed = replay(&[
+            event(0, 0, 1, 100),
+            event(1, 0, 1, 120),
+            link(2, 1, 0, 130),
+            event(0, 1, 2, 150),
+            event(1, 1, 2, 190),
+        ]);
+        assert!(completed.is_empty());
+    }
+
+    #[test]
+    fn drops_old_links_when_pending_is_full() {
+        let mut flows = Flows::default();
+        flows.enter(&event(0, 0, 1, 0));
+        for span_id in 2..MAX_PENDING as u64 + 2 {
+            flows.link(&link(span_id, 1, 0, 10));
+        }
+        assert_eq!(flows.pending.len(), MAX_PENDING);
+        // spans linked recently are kept until they exit
+        flows.link(&link(u64::MAX, 1, 0, 20));
+        assert_eq!(flows.pending.len(), MAX_PENDING);
+        assert!(!flows.pending.contains_key(&process_key(PID_TGID, u64::MAX)));
+
+        flows.enter(&event(0, 0, 1, PENDING_TIMEOUT + 10));
+        flows.link(&link(u64::MAX, 1, 0, PENDING_TIMEOUT + 10));
+        assert_eq!(flows.pending.len(), 1);
+    }
+}
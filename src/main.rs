@@ -1,50 +1,97 @@
-use std::{fmt::Display, mem::MaybeUninit, path::PathBuf, str::FromStr, time::Duration};
-
-use clap::{
-    builder::{IntoResettable, Resettable, StyledStr},
-    Parser,
+use std::{
+    mem::MaybeUninit,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
+
+use callsites::{read_name, Callsite};
+use clap::{Parser, Subcommand};
+use counters::Counters;
+use cpus::online_cpus;
+use events::{PerfEventGroup, PerfEventSpec, PerfEventSpecHelp, SUPPORTED_PERF_EVENTS};
 use eyre::{Result, WrapErr};
-use hashbrown::HashMap;
-use hdrhistogram::{iterators::IterationValue, Histogram};
+use fields::{ErrorField, FieldPredicate, GroupBy, Outcome, MAX_PREDICATES};
+use filters::{cgroup_id, CommPattern, FilterFile, Filters};
+use flows::Flows;
 use libbpf_rs::{
-    libbpf_sys::{self},
     skel::{OpenSkel, SkelBuilder},
     Link, MapCore, MapFlags, OpenObject, RingBufferBuilder,
 };
-use perf::{attach_event_with_cookie, enable_on_all_cpus, open_perf_event};
+use perf::probe_perf_event;
 use perfspan::PerfspanSkel;
-use plain::Plain;
-use tracing::{debug, error, level_filters::LevelFilter, trace, warn};
+use pmu::{core_pmu_for_cpu, CorePmu};
+use procfs::{Comms, PidNamespace};
+use profile::{Frequency, Profiler};
+use report::Report;
+use spans::{CompletedSpan, SpanTracker};
+use tracing::{debug, error, info, level_filters::LevelFilter, trace, warn};
 use tracing_subscriber::EnvFilter;
 
 mod perfspan {
     include!(concat!(env!("OUT_DIR"), "/perfspan.skel.rs"));
 }
+mod callsites;
+mod counters;
+mod cpus;
+mod events;
+mod fields;
+mod filters;
+mod flows;
 mod perf;
-
-unsafe impl Plain for perfspan::types::event {}
+mod pmu;
+mod procfs;
+mod profile;
+mod records;
+mod report;
+mod spans;
+#[cfg(test)]
+mod testing;
 
 type Event = perfspan::types::event;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(help = "path to the binary to monitor", required = true)]
-    binary: PathBuf,
-    #[clap(help = "list of spans to monitor", required = true)]
+    binary: Option<PathBuf>,
+    #[clap(
+        help = "list of spans to monitor, by name or by target and name such as my_crate::db::query",
+        required = true
+    )]
     spans: Vec<String>,
     #[clap(
         short,
         long,
-        help = "pid to monitor. if not set, all processes are monitored"
+        value_delimiter = ',',
+        help = "pids to monitor. if not set, all processes are monitored"
+    )]
+    pid: Vec<i32>,
+    #[clap(long, value_delimiter = ',', help = "thread ids to monitor")]
+    tid: Vec<u32>,
+    #[clap(
+        long,
+        help = "thread names to monitor, names ending with * are matched as prefix"
+    )]
+    comm: Vec<CommPattern>,
+    #[clap(
+        long,
+        help = "cgroup v2 to monitor, relative to /sys/fs/cgroup, e.g. system.slice/nginx.service"
+    )]
+    cgroup: Vec<PathBuf>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "file with tid, comm and cgroup filters, one per line such as `comm worker-*`. it is read again when it changes"
     )]
-    pid: Option<i32>,
+    filter_file: Option<PathBuf>,
     #[clap(
         short,
         long,
         help = PerfEventSpecHelp{},
     )]
-    events: Vec<PerfEventSpec>,
+    events: Vec<PerfEventGroup>,
     #[clap(
         short,
         long,
@@ -52,122 +99,145 @@ struct Opt {
         default_value = "10"
     )]
     buckets: u64,
+    #[clap(
+        long,
+        help = "split histograms by the core type on hybrid cpus, such as cpu_core and cpu_atom"
+    )]
+    split_by_core: bool,
+    #[clap(
+        long,
+        help = "sample stacks with the frequency, such as 99hz, while threads are inside the watched spans"
+    )]
+    profile: Option<Frequency>,
+    #[clap(
+        long,
+        help = "sample kernel stacks in addition to user stacks",
+        requires = "profile"
+    )]
+    profile_kernel: bool,
+    #[clap(
+        long,
+        help = "directory for folded stacks and flamegraphs of every span",
+        default_value = "."
+    )]
+    profile_output: PathBuf,
+    #[clap(
+        long,
+        help = "print aggregated tree of the nested spans with total and self time"
+    )]
+    tree: bool,
+    #[clap(
+        long,
+        help = "match exits with enters on any thread of the process, for spans that move between threads",
+        conflicts_with = "tree"
+    )]
+    cross_thread: bool,
+    #[clap(
+        long,
+        help = "split histograms of every span by the value of the span field, such as method"
+    )]
+    group_by: Option<String>,
+    #[clap(
+        long,
+        help = "max number of distinct values of the field per span, the rest is grouped as other",
+        default_value = "16",
+        requires = "group_by"
+    )]
+    group_by_limit: usize,
+    #[clap(
+        long = "where",
+        value_name = "FIELD=VALUE",
+        help = "record only spans with the field value, evaluated in bpf on exit. all predicates must match"
+    )]
+    predicates: Vec<FieldPredicate>,
+    #[clap(long, help = "split histograms by the process")]
+    per_pid: bool,
+    #[clap(long, help = "split histograms by the thread")]
+    per_thread: bool,
+    #[clap(
+        long,
+        help = "host pid of any process in the container. pids and the binary are resolved in its namespace"
+    )]
+    container: Option<u32>,
+    #[clap(
+        long,
+        help = "monitor children forked by the monitored processes",
+        requires = "pid"
+    )]
+    follow_children: bool,
+    #[clap(
+        long,
+        help = "record latency to tracing events emitted inside of the watched spans"
+    )]
+    phases: bool,
+    #[clap(
+        long = "value",
+        value_name = "METRIC",
+        help = "record histogram of the metric recorded with tracing_perfspan::record!"
+    )]
+    values: Vec<String>,
+    #[clap(
+        long,
+        help = "split histograms of the metrics by the innermost watched span",
+        requires = "values"
+    )]
+    values_per_span: bool,
+    #[clap(
+        long,
+        value_name = "FIELD",
+        help = "normalize latency and counters by the numeric span field, such as bytes or items"
+    )]
+    per_unit: Option<String>,
+    #[clap(
+        long,
+        help = "split histograms by outcome, spans with error or otel.status_code fields or ERROR events are errors"
+    )]
+    split_by_outcome: bool,
+    #[clap(
+        long = "error-field",
+        value_name = "FIELD",
+        help = "additional span field that marks the span as an error, as name, name=value or name>=number",
+        requires = "split_by_outcome"
+    )]
+    error_fields: Vec<ErrorField>,
+    #[clap(
+        long,
+        help = "record latency from the enter of the cause span to the watched spans that follow from it"
+    )]
+    follows_from: bool,
 }
 
-const USDT_PROVIDER: &str = "perfspan";
-const USDT_ENTER: &str = "enter";
-const USDT_EXIT: &str = "exit";
-
-struct PerfEventSpecHelp {}
-
-impl IntoResettable<StyledStr> for PerfEventSpecHelp {
-    fn into_resettable(self) -> Resettable<StyledStr> {
-        // build help string from SUPPORTED_PERF_EVENTS
-        let mut help = String::new();
-        help.push_str("supported events:\n");
-        for event in SUPPORTED_PERF_EVENTS {
-            help.push_str(&format!(" - {}\n", &event));
-        }
-        help.push_str("sample period is optional. default value is written for every event");
-        Resettable::Value(StyledStr::from(help))
+impl Opt {
+    /// Events from all groups, in the order they are assigned to counters.
+    fn perf_events(&self) -> impl Iterator<Item = &PerfEventSpec> {
+        self.events.iter().flat_map(|group| group.events.iter())
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct PerfEventSpec {
-    name: &'static str,
-    type_: u32,
-    config: u32,
-    sample_period: u64,
-}
-
-impl FromStr for PerfEventSpec {
-    type Err = eyre::Error;
-    /// Parses event spec from a string into one of the supported events.
-    /// The format is "name=period" where period is optional and name must match of the existing events
-    fn from_str(s: &str) -> Result<Self> {
-        let mut parts = s.split("=");
-        let name = parts
-            .next()
-            .ok_or_else(|| eyre::eyre!("missing event name"))?;
-        let matched = SUPPORTED_PERF_EVENTS.iter().find(|e| e.name == name);
-        eyre::ensure!(matched.is_some(), "unknown event name: {}", name);
-        let mut matched = matched.unwrap().clone();
-        if let Some(period) = parts.next() {
-            matched.sample_period = period.parse()?;
+    /// Pid for perf events, they are opened for all processes unless a single process is monitored.
+    /// Children are not known in advance, so events are opened for all processes when they are followed.
+    fn perf_pid(&self) -> i32 {
+        match self.pid.as_slice() {
+            [pid] if !self.follow_children => *pid,
+            _ => -1,
         }
-        Ok(matched)
     }
 }
 
-impl Display for PerfEventSpec {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}={}", self.name, self.sample_period)
-    }
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "list perf events and pmus supported by the host")]
+    ListEvents,
 }
 
-const SUPPORTED_PERF_EVENTS: &[PerfEventSpec] = &[
-    PerfEventSpec {
-        name: "cycles",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_CPU_CYCLES,
-        sample_period: 10_000_000,
-    },
-    PerfEventSpec {
-        name: "instructions",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_INSTRUCTIONS,
-        sample_period: 10_000_000,
-    },
-    PerfEventSpec {
-        name: "cache_references",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_CACHE_REFERENCES,
-        sample_period: 1_000,
-    },
-    PerfEventSpec {
-        name: "cache_misses",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_CACHE_MISSES,
-        sample_period: 1_000,
-    },
-    PerfEventSpec {
-        name: "branch_instructions",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
-        sample_period: 1_000_000,
-    },
-    PerfEventSpec {
-        name: "branch_misses",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_BRANCH_MISSES,
-        sample_period: 1_000_000,
-    },
-    PerfEventSpec {
-        name: "bus_cycles",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_BUS_CYCLES,
-        sample_period: 1_000_000,
-    },
-    PerfEventSpec {
-        name: "stalled_cycles_frontend",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_STALLED_CYCLES_FRONTEND,
-        sample_period: 1_000_000,
-    },
-    PerfEventSpec {
-        name: "stalled_cycles_backend",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_STALLED_CYCLES_BACKEND,
-        sample_period: 1_000_000,
-    },
-    PerfEventSpec {
-        name: "ref_cpu_cycles",
-        type_: libbpf_sys::PERF_TYPE_HARDWARE,
-        config: libbpf_sys::PERF_COUNT_HW_REF_CPU_CYCLES,
-        sample_period: 1_000_000,
-    },
-];
+const USDT_PROVIDER: &str = "perfspan";
+const USDT_ENTER: &str = "enter";
+const USDT_EXIT: &str = "exit";
+const USDT_REGISTER: &str = "register";
+const USDT_EVENT: &str = "event";
+const USDT_VALUE: &str = "value";
+const USDT_NEW: &str = "new";
+const USDT_RECORD: &str = "record";
+const USDT_FOLLOWS: &str = "follows";
 
 fn main() -> Result<()> {
     // this is set so that ring.poll doesn't exit without handing out control back to the main
@@ -183,268 +253,674 @@ fn main() -> Result<()> {
         .from_env_lossy();
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    let opt = Opt::parse();
+    let mut opt = Opt::parse();
+    if let Some(Command::ListEvents) = opt.command {
+        return list_events();
+    }
 
-    let counters_max_size = Event::default().counters.len();
     eyre::ensure!(
-        opt.events.len() <= counters_max_size,
+        opt.perf_events().count() <= MAX_EVENTS,
         "too many events requested, max is {}",
-        counters_max_size
+        MAX_EVENTS
     );
 
-    let mut open_object = MaybeUninit::uninit();
-    let (skel, _links) = register_bpf_program(&opt, &mut open_object)?;
+    eyre::ensure!(
+        opt.predicates.len() <= MAX_PREDICATES,
+        "too many predicates, max is {}",
+        MAX_PREDICATES
+    );
+    for (i, predicate) in opt.predicates.iter().enumerate() {
+        eyre::ensure!(
+            !opt.predicates[..i]
+                .iter()
+                .any(|other| other.0 == predicate.0),
+            "predicate {} is repeated",
+            predicate.0
+        );
+    }
 
-    let mut histograms_perf_span: Vec<SpanHistograms> = opt
-        .spans
-        .iter()
-        .map(|span| SpanHistograms::new(span.clone(), opt.events.iter().cloned()))
-        .collect::<Vec<_>>();
-    poll_events(skel, &mut histograms_perf_span)?;
+    let namespace = resolve_namespace(&mut opt)?;
+
+    let core_pmus = pmu::hybrid_core_pmus()?;
+    if !core_pmus.is_empty() {
+        debug!("detected hybrid cpu with core pmus: {:?}", core_pmus);
+    }
+
+    let mut filter_file = opt.filter_file.clone().map(FilterFile::new);
+    let mut open_object = MaybeUninit::uninit();
+    let (skel, _links, mut counters, mut profiler) =
+        register_bpf_program(&opt, &core_pmus, filter_file.as_mut(), &mut open_object)?;
+
+    let mut report = Report::new(
+        opt.spans.clone(),
+        opt.perf_events().cloned(),
+        opt.values.clone(),
+        opt.per_unit.clone(),
+        opt.tree,
+    );
+    let mut breakdown = Breakdown {
+        core_pmus: opt.split_by_core.then_some(core_pmus.as_slice()),
+        group_by: opt
+            .group_by
+            .clone()
+            .map(|field| GroupBy::new(field, opt.group_by_limit)),
+        outcome: opt
+            .split_by_outcome
+            .then(|| Outcome::new(opt.error_fields.clone())),
+        per_pid: opt.per_pid,
+        per_thread: opt.per_thread,
+        comms: Comms::default(),
+        namespace,
+    };
+    let tracker = if opt.cross_thread {
+        SpanTracker::cross_thread()
+    } else {
+        SpanTracker::default()
+    }
+    .with_phases(opt.phases);
+    poll_events(
+        &skel,
+        tracker,
+        opt.follows_from.then(Flows::default),
+        &mut report,
+        &mut breakdown,
+        opt.values_per_span,
+        || {
+            counters.refresh(&skel.maps.counters)?;
+            if let Some(profiler) = profiler.as_mut() {
+                profiler.refresh(&skel.progs.on_profile)?;
+            }
+            if let Some(file) = filter_file.as_mut() {
+                // filters stay as they were if the file can't be used
+                if let Err(err) = reload_filters(&skel, &opt, file) {
+                    warn!(
+                        "failed to reload filters from {}: {:#}",
+                        file.path().display(),
+                        err
+                    );
+                }
+            }
+            Ok(())
+        },
+    )?;
 
     println!(); // separate ^C from the output
-    for histogram in histograms_perf_span.iter() {
-        histogram.print(opt.buckets);
+    report.print(opt.buckets);
+    if profiler.is_some() {
+        profile::write_flamegraphs(
+            &skel.maps.stack_counts,
+            &skel.maps.stack_traces,
+            &opt.spans,
+            &opt.profile_output,
+        )?;
     }
     Ok(())
 }
 
 fn register_bpf_program<'b>(
     opt: &Opt,
+    core_pmus: &[CorePmu],
+    filter_file: Option<&mut FilterFile>,
     open_object: &'b mut MaybeUninit<OpenObject>,
-) -> Result<(PerfspanSkel<'b>, Vec<Link>)> {
+) -> Result<(PerfspanSkel<'b>, Vec<Link>, Counters, Option<Profiler>)> {
+    let binary = opt.binary.as_ref().expect("binary is required by clap");
+    // fail before attaching anything if any of the events can't be used on this host
+    let online = online_cpus()?;
+    for event in opt.perf_events() {
+        probe_event_on_host(event, core_pmus, &online)
+            .wrap_err_with(|| format!("perf event {} can't be opened", event.name))?;
+    }
+
     let mut links = vec![];
-    let builder = perfspan::PerfspanSkelBuilder::default()
+    let mut builder = perfspan::PerfspanSkelBuilder::default()
         .open(open_object)
         .wrap_err("failed to open BPF object")?;
-    builder.maps.rodata_data.cfg.filter_tgid = opt.pid.unwrap_or(0) as u32;
-    builder.maps.rodata_data.cfg.enabled_events = opt.events.len() as u32;
+    builder.maps.rodata_data.cfg.enabled_events = opt.perf_events().count() as u32;
+    // counters of every cpu are stored in the perf event array, indexed by the counter and the cpu
+    let nr_cpus = libbpf_rs::num_possible_cpus()? as u32;
+    builder.maps.rodata_data.cfg.nr_cpus = nr_cpus;
+    builder
+        .maps
+        .counters
+        .set_max_entries(MAX_EVENTS as u32 * nr_cpus)?;
+    builder.maps.rodata_data.cfg.profile = opt.profile.is_some() as u32;
+    builder.maps.rodata_data.cfg.profile_kernel = opt.profile_kernel as u32;
+    let read_fields = opt.group_by.is_some()
+        || opt.per_unit.is_some()
+        || opt.split_by_outcome
+        || !opt.predicates.is_empty();
+    builder.maps.rodata_data.cfg.read_fields = read_fields as u32;
+    builder.maps.rodata_data.cfg.predicates = opt.predicates.len() as u32;
+    builder.maps.rodata_data.cfg.follow_children = opt.follow_children as u32;
+    // tracepoints of the scheduler are loaded only when needed, as they require kernel btf
+    builder
+        .progs
+        .on_process_fork
+        .set_autoload(opt.follow_children);
+    builder
+        .progs
+        .on_process_exit
+        .set_autoload(opt.follow_children);
     let skel = builder.load()?;
+    // maps are filled before the probes are attached, otherwise spans submitted in the meantime
+    // are not filtered
+    update_filters(&skel, opt, filter_file)?;
+    for (i, predicate) in opt.predicates.iter().enumerate() {
+        debug!("filtering spans by field: {} with index {}", predicate.0, i);
+        skel.maps
+            .filter_by_field
+            .update(&predicate.key(), &(i as u8).to_ne_bytes(), MapFlags::ANY)
+            .wrap_err("failed to insert field predicate")?;
+    }
 
-    links.push(skel.progs.perfspan_enter.attach_usdt(
-        -1,
-        &opt.binary,
-        USDT_PROVIDER,
-        USDT_ENTER,
-    )?);
+    if opt.follow_children {
+        links.push(skel.progs.on_process_fork.attach()?);
+        links.push(skel.progs.on_process_exit.attach()?);
+    }
+
+    // libbpf increments usdt semaphores while probes are attached, so the layer skips the probes
+    // when perfspan is not running
+    links.push(
+        skel.progs
+            .perfspan_enter
+            .attach_usdt(-1, binary, USDT_PROVIDER, USDT_ENTER)?,
+    );
     links.push(
         skel.progs
             .perfspan_exit
-            .attach_usdt(-1, &opt.binary, USDT_PROVIDER, USDT_EXIT)?,
+            .attach_usdt(-1, binary, USDT_PROVIDER, USDT_EXIT)?,
     );
-    let pid = opt.pid.unwrap_or(-1);
-    for (cookie, event) in opt.events.iter().enumerate() {
-        let pfds = enable_on_all_cpus(|cpu| {
-            open_perf_event(
-                pid,
-                cpu,
-                event.type_,
-                event.config as u64,
-                event.sample_period,
-            )
-        })?;
-        for pfd in pfds.iter() {
-            debug!("opened perf event: {}", pfd);
-            let link =
-                attach_event_with_cookie(&skel.progs.on_perf_event, *pfd as i32, cookie as u64)?;
-            links.push(link);
-        }
+    links.push(skel.progs.perfspan_register.attach_usdt(
+        -1,
+        binary,
+        USDT_PROVIDER,
+        USDT_REGISTER,
+    )?);
+    // events are formatted by the layer only when the probe is attached, so it is opt-in
+    if opt.phases || opt.split_by_outcome {
+        links.push(
+            skel.progs
+                .perfspan_event
+                .attach_usdt(-1, binary, USDT_PROVIDER, USDT_EVENT)?,
+        );
     }
-    for (i, span) in opt.spans.iter().enumerate() {
-        let name = max_name_size_string(span);
-        debug!("watching span name: {} with index {}", span, i);
-        skel.maps
-            .filter_by_name
-            .update(&name, &(i as u8).to_ne_bytes(), MapFlags::ANY)
-            .wrap_err("failed to insert span name")?;
+    // the layer formats fields only when these probes are attached, values recorded after
+    // the span was created are needed only when fields are read
+    if read_fields {
+        links.push(
+            skel.progs
+                .perfspan_new
+                .attach_usdt(-1, binary, USDT_PROVIDER, USDT_NEW)?,
+        );
+        links.push(skel.progs.perfspan_record.attach_usdt(
+            -1,
+            binary,
+            USDT_PROVIDER,
+            USDT_RECORD,
+        )?);
     }
-    Ok((skel, links))
+    if opt.follows_from {
+        links.push(skel.progs.perfspan_follows.attach_usdt(
+            -1,
+            binary,
+            USDT_PROVIDER,
+            USDT_FOLLOWS,
+        )?);
+    }
+    if !opt.values.is_empty() {
+        links.push(
+            skel.progs
+                .perfspan_value
+                .attach_usdt(-1, binary, USDT_PROVIDER, USDT_VALUE)?,
+        );
+    }
+    let mut counters = Counters::new(
+        opt.perf_pid(),
+        opt.events.clone(),
+        core_pmus.to_vec(),
+        nr_cpus,
+    );
+    counters.open(&skel.maps.counters)?;
+    let profiler = match opt.profile {
+        Some(frequency) => {
+            let mut profiler = Profiler::new(opt.perf_pid(), frequency);
+            profiler.open(&skel.progs.on_profile)?;
+            Some(profiler)
+        }
+        None => None,
+    };
+    Ok((skel, links, counters, profiler))
 }
 
-fn poll_events(skel: PerfspanSkel<'_>, histograms_per_span: &mut [SpanHistograms]) -> Result<()> {
-    let mut open_spans = HashMap::new();
-    let mut ring = RingBufferBuilder::new();
-    ring.add(&skel.maps.events, |buf| {
-        trace!("received event {:?}", buf);
-        let ev = match plain::from_bytes::<Event>(buf) {
-            Ok(ev) => ev,
-            Err(e) => {
-                error!("failed to parse event: {:?}", e);
-                return 1;
-            }
-        };
-        match ev.r#type {
-            0 => {
-                open_spans.insert((ev.pid_tgid, ev.span_id), *ev);
-            }
-            1 => match open_spans.remove(&(ev.pid_tgid, ev.span_id)) {
-                Some(previous) => {
-                    debug!(
-                        "closing span {}/{} with latency {}. counters {:?} {:?}",
-                        ev.pid_tgid,
-                        ev.span_id,
-                        ev.timestamp - previous.timestamp,
-                        ev.counters,
-                        previous.counters
-                    );
-                    histograms_per_span[ev.name_id as usize].record_event(ev, &previous);
-                }
-                None => {
-                    warn!(
-                        "missed opening event for span {}/{}",
-                        ev.pid_tgid, ev.span_id
-                    );
-                }
-            },
-            _ => {
-                error!("unknown event type: {}", ev.r#type);
-                return 1;
-            }
+/// Finds pid namespace of the container, either set explicitly or of the monitored process.
+///
+/// Pids and thread ids are translated from the container to the host, ids that are not in the container
+/// are expected to be host ids. Binary is located through the root of the container if it exists there.
+fn resolve_namespace(opt: &mut Opt) -> Result<Option<PidNamespace>> {
+    let namespace = match (opt.container, opt.pid.first()) {
+        (Some(pid), _) => Some(PidNamespace::new(pid)?),
+        (None, Some(pid)) => PidNamespace::of_process(*pid as u32)
+            .wrap_err_with(|| format!("pid {} is not running", pid))?,
+        (None, None) => None,
+    };
+    let Some(namespace) = namespace else {
+        return Ok(None);
+    };
+    if opt.container.is_some() {
+        for pid in opt.pid.iter_mut() {
+            *pid = translate_to_host(&namespace, *pid as u32)? as i32;
         }
-        0
-    })?;
-    let ring = ring.build()?;
-    loop {
-        match ring.poll(Duration::MAX) {
-            Ok(_) => {}
-            Err(e) if e.kind() == libbpf_rs::ErrorKind::Interrupted => {
-                return Ok(());
-            }
-            Err(e) => {
-                error!("error polling ring buffer: {:?}", e);
-                eyre::bail!("error polling ring buffer: {:?}", e);
-            }
+        for tid in opt.tid.iter_mut() {
+            *tid = translate_to_host(&namespace, *tid)?;
         }
     }
+    let binary = opt.binary.as_mut().expect("binary is required by clap");
+    let in_container = namespace.path(binary);
+    if in_container.exists() {
+        debug!("using binary {} from the container", in_container.display());
+        *binary = in_container;
+    }
+    Ok(Some(namespace))
 }
 
-// this value should be consistent with value set in perfspan.h
-const MAX_NAME_SIZE: usize = 128;
+fn translate_to_host(namespace: &PidNamespace, id: u32) -> Result<u32> {
+    if let Some(host) = namespace.to_host(id)? {
+        debug!("translated {} in the container to {} on the host", id, host);
+        return Ok(host);
+    }
+    eyre::ensure!(
+        Path::new(&format!("/proc/{}", id)).exists(),
+        "{} is neither in the container nor on the host",
+        id
+    );
+    Ok(id)
+}
 
-fn max_name_size_string(s: &str) -> [u8; MAX_NAME_SIZE] {
-    let mut buf = [0; MAX_NAME_SIZE];
-    let bytes = s.as_bytes();
-    buf[..bytes.len()].copy_from_slice(bytes);
-    buf
+/// Fills maps with processes that should be monitored, and with threads, thread names and cgroups
+/// from the command line and the filter file.
+fn update_filters(
+    skel: &PerfspanSkel<'_>,
+    opt: &Opt,
+    filter_file: Option<&mut FilterFile>,
+) -> Result<()> {
+    let one = 1u8.to_ne_bytes();
+    for pid in opt.pid.iter() {
+        skel.maps
+            .filter_tgids
+            .update(&(*pid as u32).to_ne_bytes(), &one, MapFlags::ANY)
+            .wrap_err_with(|| format!("failed to insert pid {}", pid))?;
+    }
+    let filters = match filter_file {
+        Some(file) => file.reload()?.unwrap_or_default(),
+        None => Filters::default(),
+    };
+    apply_filters(skel, opt, filters)
 }
 
-struct SpanHistograms {
-    span_name: String,
-    latency: Histogram<u64>,
-    counters: Vec<(PerfEventSpec, Histogram<u64>)>,
+/// Replaces threads, thread names and cgroups in the maps if the filter file was modified.
+fn reload_filters(skel: &PerfspanSkel<'_>, opt: &Opt, file: &mut FilterFile) -> Result<()> {
+    let Some(filters) = file.reload()? else {
+        return Ok(());
+    };
+    info!("reloading filters from {}", file.path().display());
+    apply_filters(skel, opt, filters)
 }
 
-impl SpanHistograms {
-    fn new(span_name: String, perf_events: impl Iterator<Item = PerfEventSpec>) -> Self {
-        let latency = Histogram::new_with_bounds(1, u64::MAX, 3).expect("messed up arguments");
-        let counters = perf_events
-            .map(|event| {
-                (
-                    event,
-                    Histogram::new_with_bounds(1, u64::MAX, 3).expect("messed up arguments"),
-                )
-            })
-            .collect::<Vec<_>>();
-        Self {
-            span_name,
-            latency,
-            counters,
+fn apply_filters(skel: &PerfspanSkel<'_>, opt: &Opt, mut filters: Filters) -> Result<()> {
+    // tids from the command line are already translated
+    if let Some(container) = opt.container {
+        let namespace = PidNamespace::new(container)?;
+        for tid in filters.tids.iter_mut() {
+            *tid = translate_to_host(&namespace, *tid)?;
         }
     }
+    let tids = opt
+        .tid
+        .iter()
+        .chain(filters.tids.iter())
+        .map(|tid| tid.to_ne_bytes().to_vec())
+        .collect::<Vec<_>>();
+    let comms = opt
+        .comm
+        .iter()
+        .chain(filters.comms.iter())
+        .map(|comm| comm.key().to_vec())
+        .collect::<Vec<_>>();
+    let cgroups = opt
+        .cgroup
+        .iter()
+        .chain(filters.cgroups.iter())
+        .map(|cgroup| {
+            let id = cgroup_id(cgroup)?;
+            debug!("monitoring cgroup {} with id {}", cgroup.display(), id);
+            Ok(id.to_ne_bytes().to_vec())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    replace_keys(&skel.maps.filter_tids, &tids).wrap_err("failed to update threads")?;
+    replace_keys(&skel.maps.filter_comms, &comms).wrap_err("failed to update thread names")?;
+    replace_keys(&skel.maps.filter_cgroups, &cgroups).wrap_err("failed to update cgroups")?;
+    // value is struct filter_config, filters without values are disabled
+    let config = [
+        !opt.pid.is_empty(),
+        !tids.is_empty(),
+        !comms.is_empty(),
+        !cgroups.is_empty(),
+    ]
+    .map(|enabled| (enabled as u32).to_ne_bytes())
+    .concat();
+    skel.maps
+        .filter_config
+        .update(&0u32.to_ne_bytes(), &config, MapFlags::ANY)
+        .wrap_err("failed to enable filters")
+}
 
-    fn record_event(&mut self, current: &Event, previous: &Event) {
-        self.latency
-            .saturating_record(current.timestamp - previous.timestamp);
-        for (event, (_, hist)) in self.counters.iter_mut().enumerate() {
-            if current.cpu != previous.cpu {
-                warn!(
-                    "event migrated cpu from {} to {}",
-                    previous.cpu, current.cpu
-                );
-                continue;
+/// Replaces keys of the filter map. New keys are inserted before the stale ones are deleted,
+/// so that threads that match both are not dropped while the filter is updated.
+fn replace_keys(map: &impl MapCore, keys: &[Vec<u8>]) -> Result<()> {
+    let one = 1u8.to_ne_bytes();
+    for key in keys {
+        map.update(key, &one, MapFlags::ANY)?;
+    }
+    for key in map.keys().collect::<Vec<_>>() {
+        if !keys.contains(&key) {
+            map.delete(&key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens event on a single online cpu, or on a single online cpu of every core type if the cpu is hybrid.
+fn probe_event_on_host(event: &PerfEventSpec, core_pmus: &[CorePmu], online: &[u32]) -> Result<()> {
+    if core_pmus.is_empty() {
+        let cpu = online
+            .first()
+            .ok_or_else(|| eyre::eyre!("no online cpus"))?;
+        return probe_perf_event(*cpu as i32, event.type_, event.config);
+    }
+    for pmu in core_pmus {
+        let Some(cpu) = pmu.cpus.iter().find(|cpu| online.contains(cpu)) else {
+            continue;
+        };
+        probe_perf_event(
+            *cpu as i32,
+            event.type_,
+            event.config_for_pmu(Some(pmu.type_)),
+        )
+        .wrap_err_with(|| format!("not supported by {}", pmu.name))?;
+    }
+    Ok(())
+}
+
+fn list_events() -> Result<()> {
+    let core_pmus = pmu::hybrid_core_pmus()?;
+    let online = online_cpus()?;
+    println!("perf events:");
+    for event in SUPPORTED_PERF_EVENTS {
+        match probe_event_on_host(event, &core_pmus, &online) {
+            Ok(()) => println!(" - {:32} supported", event.name),
+            Err(err) => println!(" - {:32} unsupported: {:#}", event.name, err),
+        }
+    }
+    for pmu in pmu::list_pmus()? {
+        println!("pmu {} (type {}):", pmu.name, pmu.type_);
+        if !pmu.formats.is_empty() {
+            println!("  format:");
+            for (term, format) in pmu.formats.iter() {
+                println!("   - {}={}", term, format);
             }
-            if current.counters[event] < previous.counters[event] {
-                warn!(
-                    "counter {} decreased from {} to {}",
-                    event, previous.counters[event], current.counters[event]
-                );
-                continue;
+        }
+        if !pmu.events.is_empty() {
+            println!("  events:");
+            for (name, terms) in pmu.events.iter() {
+                println!("   - {}/{}/ ({})", pmu.name, name, terms);
             }
-            hist.saturating_record(current.counters[event] - previous.counters[event]);
         }
     }
+    Ok(())
+}
 
-    fn print(&self, buckets: u64) {
-        println!("SPAN: {}", self.span_name);
-        print_histogram(
-            &self.span_name,
-            "latency",
-            buckets,
-            &self.latency,
-            print_latency_distribution,
-        );
-        for (event, hist) in self.counters.iter() {
-            print_histogram(
-                &self.span_name,
-                event.name,
-                buckets,
-                hist,
-                print_counters_distribution,
-            );
+/// Labels that split histograms of the span, all enabled labels are joined into one.
+struct Breakdown<'a> {
+    core_pmus: Option<&'a [CorePmu]>,
+    group_by: Option<GroupBy>,
+    outcome: Option<Outcome>,
+    per_pid: bool,
+    per_thread: bool,
+    comms: Comms,
+    namespace: Option<PidNamespace>,
+}
+
+impl Breakdown<'_> {
+    fn label(&mut self, span: &CompletedSpan) -> String {
+        let mut labels = vec![];
+        if let Some(core_pmus) = self.core_pmus {
+            let core = core_pmu_for_cpu(core_pmus, span.exit.cpu as u32)
+                .map_or("unknown", |pmu| pmu.name.as_str());
+            labels.push(format!("core={}", core));
         }
+        if let Some(group_by) = self.group_by.as_mut() {
+            // fields include values recorded after the span was created
+            labels.push(group_by.label(span.exit.name_id, &span.fields));
+        }
+        if let Some(outcome) = self.outcome.as_ref() {
+            labels.push(outcome.label(&span.fields, span.error_event).to_string());
+        }
+        let pid = (span.exit.pid_tgid >> 32) as u32;
+        let tid = span.exit.pid_tgid as u32;
+        // pids are reported as they are seen in the container
+        let (ns_pid, ns_tid) = match self.namespace.as_mut() {
+            Some(namespace) => (
+                namespace.in_namespace(pid, pid),
+                namespace.in_namespace(pid, tid),
+            ),
+            None => (pid, tid),
+        };
+        if self.per_pid {
+            labels.push(format!("pid={} ({})", ns_pid, self.comms.comm(pid, pid)));
+        }
+        if self.per_thread {
+            labels.push(format!("tid={} ({})", ns_tid, self.comms.comm(pid, tid)));
+        }
+        labels.join(" ")
     }
 }
 
-fn print_histogram(
-    span: &str,
-    kind: &str,
-    buckets: u64,
-    hist: &Histogram<u64>,
-    print_fn: impl Fn(IterationValue<u64>, u64),
-) {
-    println!(
-        "{} {}: samples {} min {} max {} mean {:.2} stdev {:.2} p80 {} p95 {}",
-        span,
-        kind,
-        hist.len(),
-        hist.min(),
-        hist.max(),
-        hist.mean(),
-        hist.stdev(),
-        hist.value_at_quantile(0.8),
-        hist.value_at_quantile(0.95)
-    );
-    if hist.is_empty() {
-        return;
+// how often online cpus are checked for perf events that need to be opened
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn poll_events(
+    skel: &PerfspanSkel<'_>,
+    mut tracker: SpanTracker,
+    mut flows: Option<Flows>,
+    report: &mut Report,
+    breakdown: &mut Breakdown,
+    values_per_span: bool,
+    mut refresh: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    {
+        let mut ring = RingBufferBuilder::new();
+        ring.add(&skel.maps.events, |buf| {
+            trace!("received event {:?}", buf);
+            let ev = match records::decode(buf) {
+                Ok(ev) => ev,
+                Err(e) => {
+                    error!("failed to parse event: {:?}", e);
+                    return 1;
+                }
+            };
+            let ev = &ev;
+            match ev.r#type {
+                0 => {
+                    tracker.enter(ev);
+                    if let Some(flows) = flows.as_mut() {
+                        flows.enter(ev);
+                    }
+                }
+                1 => {
+                    match tracker.exit(ev) {
+                        Some(span) => {
+                            debug!(
+                                "closing span {}/{} with latency {}. counters {:?} {:?}",
+                                ev.pid_tgid,
+                                ev.span_id,
+                                span.latency(),
+                                ev.counters,
+                                span.enter.counters
+                            );
+                            if let Some(flow) =
+                                flows.as_mut().and_then(|flows| flows.complete(&span))
+                            {
+                                report.record_flow(&flow);
+                            }
+                            report.record_span(breakdown.label(&span), &span);
+                        }
+                        None => report.record_unmatched_exit(ev.name_id),
+                    }
+                    if let Some(flows) = flows.as_mut() {
+                        flows.exit(ev);
+                    }
+                }
+                2 => resolve_metric(&skel.maps.metrics, report, ev),
+                3 => resolve_callsite(&skel.maps.callsites, report, ev),
+                4 => tracker.event(ev),
+                6 => tracker.record(ev),
+                8 => {
+                    if let Some(flows) = flows.as_mut() {
+                        flows.link(ev);
+                    }
+                }
+                // span didn't match predicates on exit, it is closed without being recorded
+                7 => {
+                    if let Some(flows) = flows.as_mut() {
+                        flows.exit(ev);
+                    }
+                    tracker.discard(ev);
+                }
+                5 => {
+                    let span = values_per_span
+                        .then(|| tracker.innermost(ev.pid_tgid))
+                        .flatten();
+                    report.record_value(ev.name_id, span, ev.value);
+                }
+                _ => {
+                    error!("unknown event type: {}", ev.r#type);
+                    return 1;
+                }
+            }
+            0
+        })?;
+        let ring = ring.build()?;
+        let mut last_refresh = Instant::now();
+        loop {
+            match ring.poll(REFRESH_INTERVAL) {
+                Ok(_) => {
+                    if last_refresh.elapsed() >= REFRESH_INTERVAL {
+                        // cpus that fail are skipped, the rest keep being watched
+                        if let Err(err) = refresh() {
+                            warn!("failed to refresh perf events: {:?}", err);
+                        }
+                        last_refresh = Instant::now();
+                    }
+                }
+                Err(e) if e.kind() == libbpf_rs::ErrorKind::Interrupted => {
+                    break;
+                }
+                Err(e) => {
+                    error!("error polling ring buffer: {:?}", e);
+                    eyre::bail!("error polling ring buffer: {:?}", e);
+                }
+            }
+        }
+    }
+    // spans that are still open when polling stops
+    for name_id in tracker.open_spans() {
+        report.record_not_exited(name_id);
     }
-    let print_fn = |v| print_fn(v, hist.len());
-    hist.iter_linear(((hist.max() - hist.min()) as f64 / buckets as f64).ceil() as u64)
-        .skip_while(|v| v.quantile() < 0.01)
-        .for_each(print_fn);
+    Ok(())
 }
 
-fn print_latency_distribution(v: IterationValue<u64>, total_count: u64) {
-    println!(
-        "{:4}µs | {:40} | {:4.1}th %-ile",
-        (v.value_iterated_to() + 1) / 1_000,
-        "*".repeat(
-            (v.count_since_last_iteration() as f64 * 50.0 / total_count as f64).ceil() as usize
-        ),
-        v.percentile(),
-    );
+// this value should be consistent with value set in perfspan.bpf.c
+const CALLSITE_UNWATCHED: u8 = 0xff;
+
+/// Resolves callsite by reading it from the memory of the process, so that names are not limited
+/// by the size of bpf map keys. Spans of the callsite are dropped until it is resolved.
+fn resolve_callsite(callsites: &impl MapCore, report: &mut Report, ev: &Event) {
+    let tgid = (ev.pid_tgid >> 32) as u32;
+    let name_id = match Callsite::read(tgid, ev.span_id) {
+        Ok(callsite) => {
+            let name_id = callsite.name_id(report.spans());
+            debug!(
+                "resolved callsite {:#x} of process {} to {}::{} with index {:?}",
+                ev.span_id, tgid, callsite.target, callsite.name, name_id
+            );
+            if let (Some(name_id), Some(location)) = (name_id, callsite.location()) {
+                report.record_callsite(name_id, location);
+            }
+            name_id
+        }
+        Err(err) => {
+            warn!(
+                "failed to resolve callsite {:#x} of process {}: {:#}",
+                ev.span_id, tgid, err
+            );
+            None
+        }
+    };
+    if let Err(err) = Callsite::set_watched(tgid, ev.span_id, name_id.is_some()) {
+        warn!(
+            "failed to mark callsite {:#x} of process {}: {:#}",
+            ev.span_id, tgid, err
+        );
+    }
+    // key is struct callsite_key, with padding after tgid
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&tgid.to_ne_bytes());
+    key[8..].copy_from_slice(&ev.span_id.to_ne_bytes());
+    let value = name_id.unwrap_or(CALLSITE_UNWATCHED);
+    if let Err(err) = callsites.update(&key, &[value], MapFlags::ANY) {
+        warn!("failed to update callsite {:#x}: {}", ev.span_id, err);
+    }
 }
 
-fn print_counters_distribution(v: IterationValue<u64>, total_count: u64) {
-    println!(
-        "{:10} | {:40} | {:4.1}th %-ile",
-        v.value_iterated_to(),
-        "*".repeat(
-            (v.count_since_last_iteration() as f64 * 50.0 / total_count as f64).ceil() as usize
-        ),
-        v.percentile(),
-    );
+/// Resolves name of the metric recorded with `record!` by reading it from the memory of the process.
+/// Values of the metric are dropped until it is resolved.
+fn resolve_metric(metrics: &impl MapCore, report: &Report, ev: &Event) {
+    let tgid = (ev.pid_tgid >> 32) as u32;
+    let metric_id = match read_name(tgid, ev.span_id, ev.value) {
+        Ok(name) => {
+            let metric_id = report
+                .metrics()
+                .iter()
+                .position(|metric| *metric == name)
+                .map(|metric_id| metric_id as u8);
+            debug!(
+                "resolved metric {:#x} of process {} to {} with index {:?}",
+                ev.span_id, tgid, name, metric_id
+            );
+            metric_id
+        }
+        Err(err) => {
+            warn!(
+                "failed to resolve metric {:#x} of process {}: {:#}",
+                ev.span_id, tgid, err
+            );
+            None
+        }
+    };
+    // key is struct metric_key
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&tgid.to_ne_bytes());
+    key[4..8].copy_from_slice(&(ev.value as u32).to_ne_bytes());
+    key[8..].copy_from_slice(&ev.span_id.to_ne_bytes());
+    let value = metric_id.unwrap_or(CALLSITE_UNWATCHED);
+    if let Err(err) = metrics.update(&key, &[value], MapFlags::ANY) {
+        warn!("failed to update metric {:#x}: {}", ev.span_id, err);
+    }
 }
 
+// this value should be consistent with value set in perfspan.h
+const MAX_EVENTS: usize = 64;
+
 fn bump_memlock_rlimit() -> Result<()> {
     let rlimit = libc::rlimit {
         rlim_cur: 128 << 20,
@@ -456,4 +932,4 @@ fn bump_memlock_rlimit() -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
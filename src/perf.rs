@@ -3,23 +3,57 @@ use std::{io, mem, ptr::NonNull};
 use eyre::{Result, WrapErr};
 use libbpf_rs::{
     libbpf_sys::{
-        bpf_perf_event_opts, bpf_program__attach_perf_event_opts, libbpf_get_error, perf_event_attr, PERF_SAMPLE_RAW,
+        bpf_perf_event_opts, bpf_program__attach_perf_event_opts, libbpf_get_error,
+        perf_event_attr, PERF_COUNT_SW_CPU_CLOCK, PERF_FORMAT_TOTAL_TIME_ENABLED,
+        PERF_FORMAT_TOTAL_TIME_RUNNING, PERF_TYPE_SOFTWARE,
     },
     AsRawLibbpf, Error as BPFError, Link, ProgramMut,
 };
 use libc::{self, SYS_perf_event_open};
+use tracing::warn;
 
-pub fn enable_on_all_cpus<F: Fn(i32) -> Result<i64>>(open_event_fn: F) -> Result<Vec<i64>> {
+/// Opens events on every cpu. Cpus where events fail to open are skipped with a warning,
+/// so that a single cpu doesn't stop the others from being watched.
+pub fn enable_on_cpus<T, F: Fn(u32) -> Result<T>>(cpus: &[u32], open_event_fn: F) -> Vec<(u32, T)> {
     let mut fds = Vec::new();
-    for cpu in 0..libbpf_rs::num_possible_cpus()? {
-        let fd = open_event_fn(cpu as i32)?;
-        fds.push(fd);
+    for cpu in cpus {
+        match open_event_fn(*cpu) {
+            Ok(fd) => fds.push((*cpu, fd)),
+            Err(err) => warn!("skipping cpu {}: {:?}", cpu, err),
+        }
     }
-    Ok(fds)
+    fds
+}
+
+/// Checks that the event can be opened on the host by opening it on a single cpu and closing right away.
+pub fn probe_perf_event(cpu: i32, type_: u32, config: u64) -> Result<()> {
+    let fd = open_perf_event(-1, cpu, -1, type_, config)?;
+    unsafe { libc::close(fd as i32) };
+    Ok(())
+}
+
+/// Opens counting perf event, that is read by bpf together with the time it was enabled and running.
+/// Event is added to the group led by group_fd, or starts a new group if it is -1.
+/// Events in the same group are always scheduled on the pmu together.
+pub fn open_perf_event(pid: i32, cpu: i32, group_fd: i64, type_: u32, config: u64) -> Result<i64> {
+    let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    attr.config = config;
+    attr.type_ = type_;
+    attr.read_format = (PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+    attr.set_inherit(0);
+    perf_event_open(&attr, pid, cpu, group_fd)
 }
 
-pub fn open_perf_event(pid: i32, cpu: i32, type_: u32, config: u64, period: u64) -> Result<i64> {
-    open_event(type_, PERF_SAMPLE_RAW as u64, config, period, pid, cpu, 0)
+/// Opens cpu clock event that samples with the frequency in hz. It is used to sample stacks.
+pub fn open_cpu_clock_sampler(pid: i32, cpu: i32, frequency: u64) -> Result<i64> {
+    let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    attr.type_ = PERF_TYPE_SOFTWARE;
+    attr.config = PERF_COUNT_SW_CPU_CLOCK as u64;
+    attr.set_freq(1);
+    attr.__bindgen_anon_1.sample_freq = frequency;
+    perf_event_open(&attr, pid, cpu, -1)
 }
 
 pub fn attach_event_with_cookie(prog: &ProgramMut<'_>, pfd: i32, cookie: u64) -> Result<Link> {
@@ -37,29 +71,25 @@ pub fn attach_event_with_cookie(prog: &ProgramMut<'_>, pfd: i32, cookie: u64) ->
     Ok(link)
 }
 
-fn open_event(
-    type_: u32,
-    sample_type: u64,
-    config: u64,
-    period: u64,
-    pid: i32,
-    cpu: i32,
-    flags: u32,
-) -> Result<i64> {
-    let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
-    attr.size = mem::size_of::<perf_event_attr>() as u32;
-    attr.config = config;
-    attr.type_ = type_;
-    attr.sample_type = sample_type;
-    attr.set_inherit(0);
-    attr.__bindgen_anon_2.wakeup_events = 0;
-
-    attr.__bindgen_anon_1.sample_period = period;
-
-    let rst = unsafe { libc::syscall(SYS_perf_event_open, &attr, pid, cpu, -1, flags) };
+fn perf_event_open(attr: &perf_event_attr, pid: i32, cpu: i32, group_fd: i64) -> Result<i64> {
+    let rst = unsafe { libc::syscall(SYS_perf_event_open, attr, pid, cpu, group_fd as i32, 0) };
     match rst {
         fd @ 0.. => Ok(fd),
-        _ => eyre::bail!("io error {}/{}:", rst, io::Error::last_os_error()),
+        _ => {
+            let err = io::Error::last_os_error();
+            let reason = match err.raw_os_error() {
+                Some(libc::ENOENT | libc::EOPNOTSUPP | libc::ENODEV) => {
+                    "event is not supported by the cpu or hypervisor"
+                }
+                Some(libc::EACCES | libc::EPERM) => {
+                    "permission denied, run as root or lower kernel.perf_event_paranoid"
+                }
+                Some(libc::EINVAL) => "invalid event config",
+                Some(libc::EMFILE) => "too many open files, raise the limit with ulimit -n",
+                _ => "perf_event_open failed",
+            };
+            eyre::bail!("{}: {}", reason, err)
+        }
     }
 }
 
@@ -0,0 +1,393 @@
+use std::{
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+use tracing::warn;
+
+use crate::cpus::parse_cpu_list;
+
+const SYSFS_PMU_DEVICES: &str = "/sys/bus/event_source/devices";
+
+// pmus are looked up in the devices directory, that is SYSFS_PMU_DEVICES unless it is a test
+fn pmu_path(devices: &Path, pmu: &str) -> PathBuf {
+    devices.join(pmu)
+}
+
+fn read_trimmed(path: &Path) -> Result<String> {
+    let content =
+        fs::read_to_string(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    Ok(content.trim().to_string())
+}
+
+/// Returns perf type of the pmu, as it is expected by perf_event_open.
+fn pmu_type(devices: &Path, pmu: &str) -> Result<u32> {
+    let path = pmu_path(devices, pmu).join("type");
+    read_trimmed(&path)?
+        .parse()
+        .wrap_err_with(|| format!("invalid pmu type in {}", path.display()))
+}
+
+/// Parses pmu event in the form of "pmu/term=value,term,.../" into the pmu type and config.
+///
+/// Terms are encoded according to the sysfs format of the pmu. Term without value is either
+/// an event alias from the sysfs events directory or a flag that is set to 1.
+pub fn parse_event(s: &str) -> Result<(u32, u64)> {
+    parse_event_in(Path::new(SYSFS_PMU_DEVICES), s)
+}
+
+fn parse_event_in(devices: &Path, s: &str) -> Result<(u32, u64)> {
+    let (pmu, terms) = s
+        .strip_suffix('/')
+        .and_then(|s| s.split_once('/'))
+        .ok_or_else(|| eyre::eyre!("pmu event must be in the form pmu/term=value,.../: {}", s))?;
+    let type_ = pmu_type(devices, pmu)?;
+    let config = encode_terms(devices, pmu, terms)?;
+    Ok((type_, config))
+}
+
+fn encode_terms(devices: &Path, pmu: &str, terms: &str) -> Result<u64> {
+    let mut config = 0;
+    for term in terms.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        match term.split_once('=') {
+            Some((name, value)) => {
+                config |= read_format(devices, pmu, name)?
+                    .encode(parse_number(value)?)
+                    .wrap_err_with(|| format!("invalid value of term {} for pmu {}", name, pmu))?;
+            }
+            None => {
+                let alias = pmu_path(devices, pmu).join("events").join(term);
+                if alias.exists() {
+                    config |= encode_terms(devices, pmu, &read_trimmed(&alias)?)?;
+                } else {
+                    config |= read_format(devices, pmu, term)?.encode(1)?;
+                }
+            }
+        }
+    }
+    Ok(config)
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.wrap_err_with(|| format!("invalid term value: {}", s))
+}
+
+/// Bit layout of a single term in the perf_event_attr, as described by sysfs format file.
+/// For example "config:0-7,21" places first 8 bits of the value into 0-7 bits of config
+/// and the 9th bit into the bit 21.
+#[derive(Debug)]
+struct Format {
+    field: String,
+    bits: Vec<RangeInclusive<u32>>,
+}
+
+impl Format {
+    fn parse(s: &str) -> Result<Self> {
+        let (field, ranges) = s
+            .split_once(':')
+            .ok_or_else(|| eyre::eyre!("invalid format: {}", s))?;
+        let bits = ranges
+            .split(',')
+            .map(|range| {
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let (start, end) = (start.parse::<u32>()?, end.parse::<u32>()?);
+                // config is 64 bits, larger bits would overflow the shift in encode
+                eyre::ensure!(
+                    start <= end && end < u64::BITS,
+                    "bits {} are out of the 64 bit config",
+                    range
+                );
+                Ok(start..=end)
+            })
+            .collect::<Result<Vec<_>>>()
+            .wrap_err_with(|| format!("invalid format: {}", s))?;
+        Ok(Self {
+            field: field.to_string(),
+            bits,
+        })
+    }
+
+    fn encode(&self, value: u64) -> Result<u64> {
+        eyre::ensure!(
+            self.field == "config",
+            "only terms encoded into config are supported, got {}",
+            self.field
+        );
+        let mut encoded = 0u64;
+        let mut shift = 0u32;
+        for bit in self.bits.iter().flat_map(|range| range.clone()) {
+            if shift < u64::BITS && (value >> shift) & 1 == 1 {
+                encoded |= 1u64 << bit;
+            }
+            shift += 1;
+        }
+        eyre::ensure!(
+            shift >= u64::BITS || value >> shift == 0,
+            "value {:#x} doesn't fit into {} bits",
+            value,
+            shift
+        );
+        Ok(encoded)
+    }
+}
+
+fn read_format(devices: &Path, pmu: &str, term: &str) -> Result<Format> {
+    let path = pmu_path(devices, pmu).join("format").join(term);
+    let format =
+        read_trimmed(&path).wrap_err_with(|| format!("unknown term {} for pmu {}", term, pmu))?;
+    Format::parse(&format)
+        .wrap_err_with(|| format!("invalid format of term {} for pmu {}", term, pmu))
+}
+
+/// Pmu as it is exposed in sysfs, with event aliases and format of the terms.
+pub struct Pmu {
+    pub name: String,
+    pub type_: u32,
+    pub events: Vec<(String, String)>,
+    pub formats: Vec<(String, String)>,
+}
+
+/// Lists all pmus registered on the host, sorted by name.
+///
+/// Pmus and their entries that can't be read, which is common for restricted pmus, are skipped with a warning.
+pub fn list_pmus() -> Result<Vec<Pmu>> {
+    let devices = Path::new(SYSFS_PMU_DEVICES);
+    let mut pmus = vec![];
+    let entries = fs::read_dir(SYSFS_PMU_DEVICES)
+        .wrap_err_with(|| format!("failed to read {}", SYSFS_PMU_DEVICES))?;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("skipping pmu in {}: {}", SYSFS_PMU_DEVICES, err);
+                continue;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = pmu_path(devices, &name);
+        let type_ = match pmu_type(devices, &name) {
+            Ok(type_) => type_,
+            Err(err) => {
+                warn!("skipping pmu {}: {:#}", name, err);
+                continue;
+            }
+        };
+        pmus.push(Pmu {
+            type_,
+            events: read_dir_entries(&path.join("events")),
+            formats: read_dir_entries(&path.join("format")),
+            name,
+        });
+    }
+    pmus.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(pmus)
+}
+
+// returns sorted (file name, content) pairs. missing directory is not an error,
+// as not every pmu exposes events and format. entries that can't be read are skipped.
+fn read_dir_entries(dir: &Path) -> Vec<(String, String)> {
+    if !dir.exists() {
+        return vec![];
+    }
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) => {
+            warn!("skipping {}: {}", dir.display(), err);
+            return vec![];
+        }
+    };
+    let mut entries = vec![];
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("skipping entry in {}: {}", dir.display(), err);
+                continue;
+            }
+        };
+        // events directory also contains .scale and .unit files for some of the events
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.contains('.') {
+            continue;
+        }
+        match read_trimmed(&entry.path()) {
+            Ok(content) => entries.push((name, content)),
+            Err(err) => warn!("skipping {}: {:#}", entry.path().display(), err),
+        }
+    }
+    entries.sort();
+    entries
+}
+
+// names of the core pmus on intel hybrid cpus
+const HYBRID_CORE_PMUS: &[&str] = &["cpu_core", "cpu_atom"];
+
+/// Pmu of one of the core types on hybrid cpus.
+#[derive(Debug, Clone)]
+pub struct CorePmu {
+    pub name: String,
+    pub type_: u32,
+    pub cpus: Vec<u32>,
+}
+
+/// Returns core pmus if the cpu is hybrid, and empty list otherwise.
+pub fn hybrid_core_pmus() -> Result<Vec<CorePmu>> {
+    hybrid_core_pmus_in(Path::new(SYSFS_PMU_DEVICES))
+}
+
+fn hybrid_core_pmus_in(devices: &Path) -> Result<Vec<CorePmu>> {
+    let mut pmus = vec![];
+    for name in HYBRID_CORE_PMUS {
+        let cpus = pmu_path(devices, name).join("cpus");
+        if !cpus.exists() {
+            continue;
+        }
+        pmus.push(CorePmu {
+            name: name.to_string(),
+            type_: pmu_type(devices, name)?,
+            cpus: parse_cpu_list(&read_trimmed(&cpus)?)?,
+        });
+    }
+    Ok(pmus)
+}
+
+/// Returns pmu of the core type that the cpu belongs to.
+pub fn core_pmu_for_cpu(pmus: &[CorePmu], cpu: u32) -> Option<&CorePmu> {
+    pmus.iter().find(|pmu| pmu.cpus.contains(&cpu))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Devices directory with pmus laid out as in sysfs, removed when dropped.
+    struct FakeDevices(PathBuf);
+
+    impl FakeDevices {
+        fn new(test: &str, files: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("perfspan-{}-{}", test, std::process::id()));
+            for (path, content) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, format!("{}\n", content)).unwrap();
+            }
+            Self(dir)
+        }
+    }
+
+    impl Drop for FakeDevices {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const CPU_PMU: &[(&str, &str)] = &[
+        ("cpu/type", "4"),
+        ("cpu/format/event", "config:0-7"),
+        ("cpu/format/umask", "config:8-15"),
+        ("cpu/format/edge", "config:18"),
+        ("cpu/format/ldlat", "config1:0-15"),
+        ("cpu/events/cycles-t", "event=0x3c,in_tx=1"),
+        ("cpu/events/branches", "event=0xc4,umask=0x00"),
+    ];
+
+    #[test]
+    fn parses_terms_separated_by_commas() {
+        let devices = FakeDevices::new("terms", CPU_PMU);
+        let (type_, config) = parse_event_in(&devices.0, "cpu/event=0x3c,umask=0x01/").unwrap();
+        assert_eq!(type_, 4);
+        assert_eq!(config, 0x013c);
+    }
+
+    #[test]
+    fn sets_flags_and_expands_aliases() {
+        let devices = FakeDevices::new("aliases", CPU_PMU);
+        let (_, config) = parse_event_in(&devices.0, "cpu/event=0x3c,edge/").unwrap();
+        assert_eq!(config, 0x3c | 1 << 18);
+        let (_, config) = parse_event_in(&devices.0, "cpu/branches,umask=0x20/").unwrap();
+        assert_eq!(config, 0x20c4);
+    }
+
+    #[test]
+    fn rejects_invalid_events() {
+        let devices = FakeDevices::new("invalid", CPU_PMU);
+        // missing closing slash
+        assert!(parse_event_in(&devices.0, "cpu/event=0x3c").is_err());
+        assert!(parse_event_in(&devices.0, "cpu/unknown=1/").is_err());
+        assert!(parse_event_in(&devices.0, "missing/event=0x3c/").is_err());
+        assert!(parse_event_in(&devices.0, "cpu/event=0x100/").is_err());
+        assert!(parse_event_in(&devices.0, "cpu/event=zz/").is_err());
+        // only config is supported
+        assert!(parse_event_in(&devices.0, "cpu/ldlat=3/").is_err());
+        // alias that uses a term unknown to the pmu
+        assert!(parse_event_in(&devices.0, "cpu/cycles-t/").is_err());
+    }
+
+    #[test]
+    fn finds_core_pmus_of_hybrid_cpu() {
+        let devices = FakeDevices::new(
+            "hybrid",
+            &[
+                ("cpu_core/type", "8"),
+                ("cpu_core/cpus", "0-3,8"),
+                ("cpu_atom/type", "10"),
+                ("cpu_atom/cpus", "4-7"),
+                ("cpu/type", "4"),
+            ],
+        );
+        let pmus = hybrid_core_pmus_in(&devices.0).unwrap();
+        let names = pmus.iter().map(|pmu| pmu.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["cpu_core", "cpu_atom"]);
+        assert_eq!(pmus[0].cpus, [0, 1, 2, 3, 8]);
+        assert_eq!(core_pmu_for_cpu(&pmus, 8).unwrap().type_, 8);
+        assert_eq!(core_pmu_for_cpu(&pmus, 5).unwrap().type_, 10);
+        assert!(core_pmu_for_cpu(&pmus, 9).is_none());
+    }
+
+    #[test]
+    fn cpu_without_core_pmus_is_not_hybrid() {
+        let devices = FakeDevices::new("not-hybrid", CPU_PMU);
+        assert!(hybrid_core_pmus_in(&devices.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn encodes_split_ranges() {
+        let format = Format::parse("config:0-7,21").unwrap();
+        assert_eq!(format.encode(0x1c2).unwrap(), 0xc2 | 1 << 21);
+    }
+
+    #[test]
+    fn rejects_value_wider_than_format() {
+        let format = Format::parse("config:0-7").unwrap();
+        assert!(format.encode(0x100).is_err());
+    }
+
+    #[test]
+    fn rejects_bits_out_of_config() {
+        assert!(Format::parse("config:60-70").is_err());
+        assert!(Format::parse("config:64").is_err());
+        assert!(Format::parse("config:7-0").is_err());
+        assert!(Format::parse("config:63").is_ok());
+    }
+
+    #[test]
+    fn skips_entries_that_cant_be_read() {
+        let dir = std::env::temp_dir().join(format!("perfspan-pmu-{}", std::process::id()));
+        fs::create_dir_all(dir.join("unreadable")).unwrap();
+        fs::write(dir.join("cycles"), "event=0x3c\n").unwrap();
+        fs::write(dir.join("cycles.unit"), "events\n").unwrap();
+        let entries = read_dir_entries(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            entries,
+            vec![("cycles".to_string(), "event=0x3c".to_string())]
+        );
+    }
+}
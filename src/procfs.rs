@@ -0,0 +1,117 @@
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+use hashbrown::HashMap;
+
+/// Names of the threads read from procfs, cached by the thread id.
+///
+/// Name is read when the thread is seen for the first time, threads that exited by then are unknown.
+#[derive(Default)]
+pub struct Comms {
+    names: HashMap<u32, String>,
+}
+
+impl Comms {
+    /// Name of the thread, pass pid as tid for the name of the process.
+    pub fn comm(&mut self, pid: u32, tid: u32) -> &str {
+        self.names.entry(tid).or_insert_with(|| {
+            fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_else(|_| "unknown".to_string())
+        })
+    }
+}
+
+/// Pid namespace of the monitored container, perfspan itself is expected to run on the host.
+///
+/// Bpf and perf events see pids from the host namespace, while users see pids from the container,
+/// pids are translated using NSpid from /proc/<pid>/status.
+pub struct PidNamespace {
+    // host pid of a process in the namespace
+    pid: u32,
+    ns: u64,
+    ns_pids: HashMap<u32, u32>,
+}
+
+impl PidNamespace {
+    pub fn new(pid: u32) -> Result<Self> {
+        Ok(Self {
+            pid,
+            ns: pid_namespace(pid)?,
+            ns_pids: HashMap::new(),
+        })
+    }
+
+    /// Namespace of the process if it is different from the namespace of perfspan.
+    pub fn of_process(pid: u32) -> Result<Option<Self>> {
+        let ns = Self::new(pid)?;
+        let own = fs::metadata("/proc/self/ns/pid")
+            .wrap_err("failed to read pid namespace of perfspan")?
+            .ino();
+        Ok((ns.ns != own).then_some(ns))
+    }
+
+    /// Path to the file in the mount namespace of the container.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        let root = PathBuf::from(format!("/proc/{}/root", self.pid));
+        root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Translates pid or tid from the namespace to the host.
+    pub fn to_host(&self, id: u32) -> Result<Option<u32>> {
+        for pid in read_ids(Path::new("/proc"))? {
+            if pid_namespace(pid).ok() != Some(self.ns) {
+                continue;
+            }
+            for tid in read_ids(&PathBuf::from(format!("/proc/{}/task", pid))).unwrap_or_default() {
+                if ns_pids(pid, tid).ok().and_then(|ids| ids.last().copied()) == Some(id) {
+                    return Ok(Some(tid));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Translates pid or tid from the host to the namespace, ids that can't be translated are kept.
+    pub fn in_namespace(&mut self, pid: u32, tid: u32) -> u32 {
+        *self.ns_pids.entry(tid).or_insert_with(|| {
+            ns_pids(pid, tid)
+                .ok()
+                .and_then(|ids| ids.last().copied())
+                .unwrap_or(tid)
+        })
+    }
+}
+
+fn pid_namespace(pid: u32) -> Result<u64> {
+    let path = format!("/proc/{}/ns/pid", pid);
+    Ok(fs::metadata(&path)
+        .wrap_err_with(|| format!("failed to read {}", path))?
+        .ino())
+}
+
+// ids of the thread in every nested namespace, from the host to the innermost
+fn ns_pids(pid: u32, tid: u32) -> Result<Vec<u32>> {
+    let path = format!("/proc/{}/task/{}/status", pid, tid);
+    let status = fs::read_to_string(&path).wrap_err_with(|| format!("failed to read {}", path))?;
+    let Some(line) = status.lines().find_map(|line| line.strip_prefix("NSpid:")) else {
+        eyre::bail!("NSpid is missing in {}", path);
+    };
+    line.split_whitespace()
+        .map(|id| {
+            id.parse()
+                .wrap_err_with(|| format!("invalid NSpid in {}", path))
+        })
+        .collect()
+}
+
+fn read_ids(dir: &Path) -> Result<Vec<u32>> {
+    Ok(fs::read_dir(dir)
+        .wrap_err_with(|| format!("failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect())
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use blazesym::{
+    symbolize::{
+        source::{Kernel, Process, Source},
+        Input, Symbolized, Symbolizer,
+    },
+    Pid,
+};
+use eyre::{Result, WrapErr};
+use hashbrown::HashMap;
+use inferno::flamegraph;
+use libbpf_rs::{Link, MapCore, MapFlags, ProgramMut};
+use plain::Plain;
+use tracing::{debug, info, warn};
+
+use crate::{
+    cpus::online_cpus,
+    perf::{attach_event_with_cookie, enable_on_cpus, open_cpu_clock_sampler},
+    perfspan,
+};
+
+unsafe impl Plain for perfspan::types::stack_key {}
+
+type StackKey = perfspan::types::stack_key;
+
+/// Sampling frequency in the form of "99hz" or "99".
+#[derive(Debug, Clone, Copy)]
+pub struct Frequency(pub u64);
+
+impl FromStr for Frequency {
+    type Err = eyre::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let hz = s.strip_suffix("hz").unwrap_or(s);
+        let frequency = hz
+            .parse()
+            .wrap_err_with(|| format!("invalid sampling frequency: {}", s))?;
+        eyre::ensure!(frequency > 0, "sampling frequency must be positive");
+        Ok(Self(frequency))
+    }
+}
+
+/// Cpu clock samplers opened on every online cpu. Samples are taken only when the thread
+/// is inside one of the watched spans, stacks are aggregated per span in bpf.
+///
+/// Cpus where samplers failed to open are skipped until they go offline and online again.
+pub struct Profiler {
+    pid: i32,
+    frequency: Frequency,
+    links_per_cpu: BTreeMap<u32, Link>,
+    failed: BTreeSet<u32>,
+}
+
+impl Profiler {
+    pub fn new(pid: i32, frequency: Frequency) -> Self {
+        Self {
+            pid,
+            frequency,
+            links_per_cpu: BTreeMap::new(),
+            failed: BTreeSet::new(),
+        }
+    }
+
+    pub fn open(&mut self, prog: &ProgramMut<'_>) -> Result<()> {
+        self.open_on_cpus(prog, &online_cpus()?)
+    }
+
+    /// Opens samplers on cpus that came online since the last call.
+    pub fn refresh(&mut self, prog: &ProgramMut<'_>) -> Result<()> {
+        let online = online_cpus()?;
+        self.links_per_cpu.retain(|cpu, _| online.contains(cpu));
+        self.failed.retain(|cpu| online.contains(cpu));
+        let new = online
+            .into_iter()
+            .filter(|cpu| !self.links_per_cpu.contains_key(cpu) && !self.failed.contains(cpu))
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            return Ok(());
+        }
+        info!("opening stack samplers on cpus {:?} that came online", new);
+        self.open_on_cpus(prog, &new)
+    }
+
+    fn open_on_cpus(&mut self, prog: &ProgramMut<'_>, cpus: &[u32]) -> Result<()> {
+        // cpus are marked as failed until the sampler is attached
+        self.failed.extend(cpus);
+        let links = enable_on_cpus(cpus, |cpu| {
+            let pfd = open_cpu_clock_sampler(self.pid, cpu as i32, self.frequency.0)
+                .wrap_err("failed to open cpu clock sampler")?;
+            debug!("opened stack sampler: {} on cpu {}", pfd, cpu);
+            attach_event_with_cookie(prog, pfd as i32, 0).inspect_err(|_| {
+                // SAFETY: sampler was just opened and isn't owned by a link
+                unsafe { libc::close(pfd as i32) };
+            })
+        });
+        for (cpu, link) in links {
+            self.failed.remove(&cpu);
+            self.links_per_cpu.insert(cpu, link);
+        }
+        Ok(())
+    }
+}
+
+/// Symbolizes stacks sampled within every span and writes them into the directory
+/// as folded stacks and as flamegraph, one pair of files per span.
+pub fn write_flamegraphs(
+    stack_counts: &impl MapCore,
+    stack_traces: &impl MapCore,
+    spans: &[String],
+    dir: &Path,
+) -> Result<()> {
+    let symbolizer = Symbolizer::new();
+    let mut folded_per_span: Vec<HashMap<String, u64>> = vec![HashMap::new(); spans.len()];
+    for key in stack_counts.keys() {
+        let Some(count) = stack_counts.lookup(&key, MapFlags::ANY)? else {
+            continue;
+        };
+        let mut stack_key = StackKey::default();
+        plain::copy_from_bytes(&mut stack_key, &key)
+            .map_err(|e| eyre::eyre!("failed to parse stack key: {:?}", e))?;
+        let count = u64::from_ne_bytes(count[..8].try_into()?);
+
+        // folded stacks start from the outermost frame, kernel frames are on top of user frames
+        let mut frames = vec![];
+        if stack_key.user_stack_id >= 0 {
+            let addrs = read_stack(stack_traces, stack_key.user_stack_id)?;
+            let src = Source::Process(Process::new(Pid::from(stack_key.tgid)));
+            frames.extend(symbolize(&symbolizer, &src, &addrs).into_iter().rev());
+        }
+        if stack_key.kernel_stack_id >= 0 {
+            let addrs = read_stack(stack_traces, stack_key.kernel_stack_id)?;
+            let src = Source::Kernel(Kernel::default());
+            frames.extend(symbolize(&symbolizer, &src, &addrs).into_iter().rev());
+        }
+        if frames.is_empty() {
+            continue;
+        }
+        let Some(folded) = folded_per_span.get_mut(stack_key.name_id as usize) else {
+            continue;
+        };
+        *folded.entry(frames.join(";")).or_default() += count;
+    }
+
+    fs::create_dir_all(dir).wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+    for (span, folded) in spans.iter().zip(folded_per_span) {
+        if folded.is_empty() {
+            continue;
+        }
+        let mut lines = folded
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}", stack, count))
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        let name = span.replace(['/', ':'], "_");
+        let folded_path = dir.join(format!("{}.folded", name));
+        let mut writer = BufWriter::new(File::create(&folded_path)?);
+        for line in lines.iter() {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.flush()?;
+
+        let svg_path = dir.join(format!("{}.svg", name));
+        let mut options = flamegraph::Options::default();
+        options.title = format!("span {}", span);
+        flamegraph::from_lines(
+            &mut options,
+            lines.iter().map(String::as_str),
+            BufWriter::new(File::create(&svg_path)?),
+        )
+        .wrap_err_with(|| format!("failed to write flamegraph for span {}", span))?;
+        println!(
+            "SPAN: {} stacks written to {} and {}",
+            span,
+            folded_path.display(),
+            svg_path.display()
+        );
+    }
+    Ok(())
+}
+
+fn read_stack(stack_traces: &impl MapCore, stack_id: i32) -> Result<Vec<u64>> {
+    let Some(stack) = stack_traces.lookup(&(stack_id as u32).to_ne_bytes(), MapFlags::ANY)? else {
+        return Ok(vec![]);
+    };
+    Ok(stack
+        .chunks_exact(8)
+        .map(|addr| u64::from_ne_bytes(addr.try_into().expect("chunk is 8 bytes")))
+        .take_while(|addr| *addr != 0)
+        .collect())
+}
+
+// returns symbol for every address, addresses that can't be symbolized are formatted as hex
+fn symbolize(symbolizer: &Symbolizer, src: &Source<'_>, addrs: &[u64]) -> Vec<String> {
+    match symbolizer.symbolize(src, Input::AbsAddr(addrs)) {
+        Ok(symbols) => symbols
+            .iter()
+            .zip(addrs)
+            .map(|(symbol, addr)| match symbol {
+                Symbolized::Sym(sym) => sym.name.to_string(),
+                Symbolized::Unknown(..) => format!("{:#x}", addr),
+            })
+            .collect(),
+        Err(err) => {
+            warn!("failed to symbolize stack: {}", err);
+            addrs.iter().map(|addr| format!("{:#x}", addr)).collect()
+        }
+    }
+}
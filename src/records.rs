@@ -0,0 +1,125 @@
+use std::mem;
+
+use eyre::Result;
+use plain::Plain;
+
+use crate::{perfspan, Event};
+
+type Header = perfspan::types::record;
+type CounterValue = perfspan::types::counter_value;
+
+unsafe impl Plain for Header {}
+unsafe impl Plain for CounterValue {}
+
+/// Expands the record read from the ring buffer into the event.
+///
+/// Records carry only the counters and fields that were read by bpf, the rest of the event is zeroed.
+pub fn decode(buf: &[u8]) -> Result<Event> {
+    let header_size = mem::size_of::<Header>();
+    eyre::ensure!(
+        buf.len() >= header_size,
+        "record of {} bytes is shorter than the header",
+        buf.len()
+    );
+    let mut header = Header::default();
+    plain::copy_from_bytes(&mut header, &buf[..header_size])
+        .map_err(|e| eyre::eyre!("failed to parse record header: {:?}", e))?;
+    let mut ev = Event {
+        r#type: header.r#type,
+        name_id: header.name_id,
+        cpu: header.cpu,
+        level: header.level,
+        cause_name_id: header.cause_name_id,
+        span_id: header.span_id,
+        pid_tgid: header.pid_tgid,
+        timestamp: header.timestamp,
+        value: header.value,
+        ..Default::default()
+    };
+
+    let nr_counters = header.nr_counters as usize;
+    let fields_size = header.fields_size as usize;
+    let counters_size = nr_counters * mem::size_of::<CounterValue>();
+    eyre::ensure!(
+        nr_counters <= ev.counters.len()
+            && fields_size <= ev.fields.len()
+            && buf.len() >= header_size + counters_size + fields_size,
+        "record of {} bytes doesn't fit {} counters and {} bytes of fields",
+        buf.len(),
+        nr_counters,
+        fields_size
+    );
+    let data = &buf[header_size..];
+    for (i, bytes) in data[..counters_size]
+        .chunks_exact(mem::size_of::<CounterValue>())
+        .enumerate()
+    {
+        let mut value = CounterValue::default();
+        plain::copy_from_bytes(&mut value, bytes)
+            .map_err(|e| eyre::eyre!("failed to parse counter: {:?}", e))?;
+        ev.counters[i] = value.counter;
+        ev.enabled[i] = value.enabled;
+        ev.running[i] = value.running;
+    }
+    ev.fields[..fields_size].copy_from_slice(&data[counters_size..counters_size + fields_size]);
+    Ok(ev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(nr_counters: u8, fields_size: u16) -> Header {
+        Header {
+            r#type: 1,
+            name_id: 2,
+            cpu: 3,
+            nr_counters,
+            fields_size,
+            span_id: 4,
+            pid_tgid: 5,
+            timestamp: 6,
+            ..Default::default()
+        }
+    }
+
+    fn encode(header: &Header, counters: &[[u64; 3]], fields: &[u8]) -> Vec<u8> {
+        let mut buf = unsafe { plain::as_bytes(header) }.to_vec();
+        for value in counters.iter().flatten() {
+            buf.extend_from_slice(&value.to_ne_bytes());
+        }
+        buf.extend_from_slice(fields);
+        buf
+    }
+
+    #[test]
+    fn decodes_counters_and_fields() {
+        let buf = encode(
+            &header(2, 11),
+            &[[100, 10, 10], [200, 10, 5]],
+            b"status=200\0",
+        );
+        let ev = decode(&buf).unwrap();
+        assert_eq!((ev.r#type, ev.name_id, ev.cpu), (1, 2, 3));
+        assert_eq!((ev.span_id, ev.pid_tgid, ev.timestamp), (4, 5, 6));
+        assert_eq!(ev.counters[..3], [100, 200, 0]);
+        assert_eq!(ev.enabled[..2], [10, 10]);
+        assert_eq!(ev.running[..2], [10, 5]);
+        assert_eq!(&ev.fields[..12], b"status=200\0\0");
+    }
+
+    #[test]
+    fn decodes_header_only_record() {
+        let ev = decode(&encode(&header(0, 0), &[], b"")).unwrap();
+        assert_eq!(ev.span_id, 4);
+        assert!(ev.counters.iter().all(|counter| *counter == 0));
+        assert!(ev.fields.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn rejects_truncated_record() {
+        let buf = encode(&header(2, 11), &[[100, 10, 10]], b"");
+        assert!(decode(&buf).is_err());
+        assert!(decode(&buf[..8]).is_err());
+    }
+}
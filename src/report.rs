@@ -0,0 +1,669 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use hdrhistogram::{iterators::IterationValue, Histogram};
+use tracing::warn;
+
+use crate::{events::PerfEventSpec, fields::field_value, flows::Flow, spans::CompletedSpan, Event};
+
+/// Histograms for all watched spans. Histograms of a span are split into several
+/// if the label is not empty, for example by the core type of the cpu.
+pub struct Report {
+    spans: Vec<String>,
+    events: Vec<PerfEventSpec>,
+    histograms: BTreeMap<(usize, String), SpanHistograms>,
+    // aggregated call tree of the spans, keyed by the path from the outermost span
+    tree: Option<BTreeMap<Vec<u8>, TreeNode>>,
+    mismatches: Vec<Mismatches>,
+    // spans that exited on a different thread than they entered
+    handoffs: Vec<u64>,
+    // source locations of the spans, several spans may have the same name
+    callsites: Vec<BTreeSet<String>>,
+    metrics: Vec<String>,
+    // numeric field that latency and counters are normalized by
+    per_unit: Option<String>,
+    // values of the metrics, optionally split by the innermost watched span
+    values: BTreeMap<(u8, Option<u8>), Histogram<u64>>,
+    // latency from the cause span to the span that follows from it
+    flows: BTreeMap<(u8, u8), FlowHistograms>,
+}
+
+struct FlowHistograms {
+    to_enter: Histogram<u64>,
+    to_exit: Histogram<u64>,
+}
+
+/// Enters and exits of the span that were not properly nested.
+#[derive(Default)]
+struct Mismatches {
+    reentered: u64,
+    out_of_order: u64,
+    unmatched_exits: u64,
+    not_exited: u64,
+}
+
+#[derive(Default)]
+struct TreeNode {
+    count: u64,
+    total: u64,
+    self_time: u64,
+}
+
+impl Report {
+    pub fn new(
+        spans: Vec<String>,
+        events: impl Iterator<Item = PerfEventSpec>,
+        metrics: Vec<String>,
+        per_unit: Option<String>,
+        tree: bool,
+    ) -> Self {
+        Self {
+            mismatches: spans.iter().map(|_| Mismatches::default()).collect(),
+            handoffs: vec![0; spans.len()],
+            callsites: vec![BTreeSet::new(); spans.len()],
+            spans,
+            events: events.collect(),
+            histograms: BTreeMap::new(),
+            tree: tree.then(BTreeMap::new),
+            metrics,
+            per_unit,
+            values: BTreeMap::new(),
+            flows: BTreeMap::new(),
+        }
+    }
+
+    pub fn record_flow(&mut self, flow: &Flow) {
+        let histograms = self
+            .flows
+            .entry((flow.cause, flow.effect))
+            .or_insert_with(|| FlowHistograms {
+                to_enter: Histogram::new_with_bounds(1, u64::MAX, 3).expect("messed up arguments"),
+                to_exit: Histogram::new_with_bounds(1, u64::MAX, 3).expect("messed up arguments"),
+            });
+        histograms.to_enter.saturating_record(flow.to_enter);
+        histograms.to_exit.saturating_record(flow.to_exit);
+    }
+
+    pub fn record_value(&mut self, metric_id: u8, span: Option<u8>, value: u64) {
+        self.values
+            .entry((metric_id, span))
+            .or_insert_with(|| {
+                Histogram::new_with_bounds(1, u64::MAX, 3).expect("messed up arguments")
+            })
+            .saturating_record(value);
+    }
+
+    pub fn spans(&self) -> &[String] {
+        &self.spans
+    }
+
+    pub fn metrics(&self) -> &[String] {
+        &self.metrics
+    }
+
+    /// Records source location of the span, several callsites may match the same span.
+    pub fn record_callsite(&mut self, name_id: u8, location: String) {
+        self.callsites[name_id as usize].insert(location);
+    }
+
+    pub fn record_unmatched_exit(&mut self, name_id: u8) {
+        self.mismatches[name_id as usize].unmatched_exits += 1;
+    }
+
+    pub fn record_not_exited(&mut self, name_id: u8) {
+        self.mismatches[name_id as usize].not_exited += 1;
+    }
+
+    pub fn record_span(&mut self, label: String, span: &CompletedSpan) {
+        let mismatches = &mut self.mismatches[span.exit.name_id as usize];
+        mismatches.reentered += span.reentered as u64;
+        mismatches.out_of_order += span.out_of_order as u64;
+        self.handoffs[span.exit.name_id as usize] += span.handoff as u64;
+        if let Some(tree) = self.tree.as_mut() {
+            let node = tree.entry(span.path.clone()).or_default();
+            node.count += 1;
+            node.total += span.latency();
+            node.self_time += span.self_time();
+        }
+        let Self {
+            spans,
+            events,
+            histograms,
+            per_unit,
+            ..
+        } = self;
+        histograms
+            .entry((span.exit.name_id as usize, label))
+            .or_insert_with_key(|(name_id, label)| {
+                let title = if label.is_empty() {
+                    spans[*name_id].clone()
+                } else {
+                    format!("{} {}", spans[*name_id], label)
+                };
+                SpanHistograms::new(title, events.iter().cloned(), per_unit.as_deref())
+            })
+            .record_span(span);
+    }
+
+    pub fn print(&self, buckets: u64) {
+        for (name_id, span) in self.spans.iter().enumerate() {
+            let mut recorded = self
+                .histograms
+                .range((name_id, String::new())..(name_id + 1, String::new()))
+                .peekable();
+            if recorded.peek().is_none() {
+                SpanHistograms::new(
+                    span.clone(),
+                    self.events.iter().cloned(),
+                    self.per_unit.as_deref(),
+                )
+                .print(&self.spans, buckets);
+            }
+            let mut samples = 0;
+            for (_, histograms) in recorded {
+                histograms.print(&self.spans, buckets);
+                samples += histograms.latency.len();
+            }
+            let callsites = &self.callsites[name_id];
+            if !callsites.is_empty() {
+                println!(
+                    "{} callsites: {}",
+                    span,
+                    callsites.iter().cloned().collect::<Vec<_>>().join(", ")
+                );
+            }
+            let handoffs = self.handoffs[name_id];
+            if handoffs > 0 {
+                println!(
+                    "{} handoffs: {} {:.1}% exited on a different thread",
+                    span,
+                    handoffs,
+                    percent(handoffs, samples)
+                );
+            }
+            let mismatches = &self.mismatches[name_id];
+            if mismatches.reentered
+                + mismatches.out_of_order
+                + mismatches.unmatched_exits
+                + mismatches.not_exited
+                > 0
+            {
+                println!(
+                    "{} mismatches: re-entered {} exited out of order {} exited without enter {} not exited {}",
+                    span,
+                    mismatches.reentered,
+                    mismatches.out_of_order,
+                    mismatches.unmatched_exits,
+                    mismatches.not_exited
+                );
+            }
+        }
+        for ((metric_id, span), hist) in self.values.iter() {
+            let metric = &self.metrics[*metric_id as usize];
+            let title = match span {
+                Some(span) => format!("{} in {}", metric, self.spans[*span as usize]),
+                None => metric.clone(),
+            };
+            println!("VALUE: {}", title);
+            print_histogram(&title, "value", buckets, hist, print_counters_distribution);
+        }
+        for ((cause, effect), histograms) in self.flows.iter() {
+            let title = format!(
+                "{} -> {}",
+                self.spans[*cause as usize], self.spans[*effect as usize]
+            );
+            println!("FLOW: {}", title);
+            print_histogram(
+                &title,
+                "cause enter to enter",
+                buckets,
+                &histograms.to_enter,
+                print_latency_distribution,
+            );
+            print_histogram(
+                &title,
+                "cause enter to exit",
+                buckets,
+                &histograms.to_exit,
+                print_latency_distribution,
+            );
+        }
+        if let Some(tree) = self.tree.as_ref() {
+            self.print_tree(tree);
+        }
+    }
+
+    fn print_tree(&self, tree: &BTreeMap<Vec<u8>, TreeNode>) {
+        println!("SPAN TREE:");
+        // tree is ordered by path, therefore every node is printed right after its parent
+        for (path, node) in tree.iter() {
+            let root = tree.get(&path[..1]).map_or(node.total, |root| root.total);
+            let name = &self.spans[*path.last().expect("path is not empty") as usize];
+            println!(
+                "{:indent$}{:width$} count {:8} total {:10}µs {:5.1}% self {:10}µs {:5.1}%",
+                "",
+                name,
+                node.count,
+                node.total / 1_000,
+                percent(node.total, root),
+                node.self_time / 1_000,
+                percent(node.self_time, root),
+                indent = (path.len() - 1) * 2,
+                width = 40usize.saturating_sub((path.len() - 1) * 2),
+            );
+        }
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    part as f64 * 100.0 / total as f64
+}
+
+struct SpanHistograms {
+    span_name: String,
+    latency: Histogram<u64>,
+    // time excluding watched children, it is the latency for instances without children
+    self_time: Histogram<u64>,
+    total_time: u64,
+    children: BTreeMap<u8, u64>,
+    counters: Vec<CounterHistogram>,
+    // events inside the span by message, in the order they were first seen
+    phases: Vec<PhaseHistograms>,
+    units: Option<UnitHistograms>,
+}
+
+// values per unit are recorded per 1000 units, so that fractions like ns per byte are not lost
+const UNITS_SCALE: u64 = 1000;
+
+struct UnitHistograms {
+    field: String,
+    latency: Histogram<u64>,
+    // units per second
+    throughput: Histogram<u64>,
+    // spans without the field, or with a value that is not a positive number
+    skipped: u64,
+}
+
+// events with distinct messages that are tracked per span, messages with formatted values are all distinct
+const MAX_PHASES: usize = 32;
+
+struct PhaseHistograms {
+    message: String,
+    since_enter: Histogram<u64>,
+    // latency from the previous event in the span, or from enter for the first one
+    since_previous: Histogram<u64>,
+}
+
+struct CounterHistogram {
+    event: PerfEventSpec,
+    hist: Histogram<u64>,
+    // total time the counter was enabled and running within recorded spans
+    enabled: u64,
+    running: u64,
+    // counter per 1000 units, if spans are normalized by a field
+    per_unit: Option<Histogram<u64>>,
+}
+
+impl SpanHistograms {
+    fn new(
+        span_name: String,
+        perf_events: impl Iterator<Item = PerfEventSpec>,
+        per_unit: Option<&str>,
+    ) -> Self {
+        let new_histogram =
+            || Histogram::new_with_bounds(1, u64::MAX, 3).expect("messed up arguments");
+        let latency = new_histogram();
+        let counters = perf_events
+            .map(|event| CounterHistogram {
+                event,
+                hist: new_histogram(),
+                enabled: 0,
+                running: 0,
+                per_unit: per_unit.map(|_| new_histogram()),
+            })
+            .collect::<Vec<_>>();
+        let units = per_unit.map(|field| UnitHistograms {
+            field: field.to_string(),
+            latency: new_histogram(),
+            throughput: new_histogram(),
+            skipped: 0,
+        });
+        Self {
+            span_name,
+            latency,
+            self_time: new_histogram(),
+            total_time: 0,
+            children: BTreeMap::new(),
+            counters,
+            phases: vec![],
+            units,
+        }
+    }
+
+    fn record_span(&mut self, span: &CompletedSpan) {
+        // fields include values recorded after the span was entered
+        let units = self.units.as_mut().and_then(|units| {
+            let value = field_value(&span.fields, &units.field)
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|value| *value > 0);
+            if value.is_none() {
+                units.skipped += 1;
+            }
+            value
+        });
+        self.record_event(&span.exit, &span.enter, units);
+        self.total_time += span.latency();
+        self.record_phases(span);
+        self.self_time.saturating_record(span.self_time());
+        for (child, time) in span.children.iter() {
+            *self.children.entry(*child).or_default() += time;
+        }
+    }
+
+    fn record_phases(&mut self, span: &CompletedSpan) {
+        let mut previous = span.enter.timestamp;
+        for phase in span.phases.iter() {
+            let position = self
+                .phases
+                .iter()
+                .position(|recorded| recorded.message == phase.message);
+            let histograms = match position {
+                Some(position) => &mut self.phases[position],
+                None if self.phases.len() < MAX_PHASES => {
+                    self.phases.push(PhaseHistograms {
+                        message: phase.message.clone(),
+                        since_enter: Histogram::new_with_bounds(1, u64::MAX, 3)
+                            .expect("messed up arguments"),
+                        since_previous: Histogram::new_with_bounds(1, u64::MAX, 3)
+                            .expect("messed up arguments"),
+                    });
+                    self.phases.last_mut().expect("just pushed")
+                }
+                None => continue,
+            };
+            histograms
+                .since_enter
+                .saturating_record(phase.timestamp.saturating_sub(span.enter.timestamp));
+            histograms
+                .since_previous
+                .saturating_record(phase.timestamp.saturating_sub(previous));
+            previous = phase.timestamp;
+        }
+    }
+
+    fn record_event(&mut self, current: &Event, previous: &Event, units: Option<u64>) {
+        let latency = current.timestamp - previous.timestamp;
+        self.latency.saturating_record(latency);
+        if let (Some(histograms), Some(units)) = (self.units.as_mut(), units) {
+            histograms
+                .latency
+                .saturating_record(per_units(latency, units));
+            if latency > 0 {
+                histograms
+                    .throughput
+                    .saturating_record((units as u128 * 1_000_000_000 / latency as u128) as u64);
+            }
+        }
+        for (event, counter) in self.counters.iter_mut().enumerate() {
+            if current.cpu != previous.cpu {
+                warn!(
+                    "event migrated cpu from {} to {}",
+                    previous.cpu, current.cpu
+                );
+                continue;
+            }
+            if current.counters[event] < previous.counters[event] {
+                warn!(
+                    "counter {} decreased from {} to {}",
+                    event, previous.counters[event], current.counters[event]
+                );
+                continue;
+            }
+            let enabled = current.enabled[event].saturating_sub(previous.enabled[event]);
+            let running = current.running[event].saturating_sub(previous.running[event]);
+            counter.enabled += enabled;
+            counter.running += running;
+            let Some(delta) = scale_counter(
+                current.counters[event] - previous.counters[event],
+                enabled,
+                running,
+            ) else {
+                continue;
+            };
+            counter.hist.saturating_record(delta);
+            if let (Some(hist), Some(units)) = (counter.per_unit.as_mut(), units) {
+                hist.saturating_record(per_units(delta, units));
+            }
+        }
+    }
+
+    fn print(&self, spans: &[String], buckets: u64) {
+        println!("SPAN: {}", self.span_name);
+        print_histogram(
+            &self.span_name,
+            "latency",
+            buckets,
+            &self.latency,
+            print_latency_distribution,
+        );
+        if !self.children.is_empty() {
+            print_histogram(
+                &self.span_name,
+                "self time",
+                buckets,
+                &self.self_time,
+                print_latency_distribution,
+            );
+            let children = self.children.values().sum::<u64>();
+            println!(
+                "{} time: total {}µs self {}µs {:.1}%",
+                self.span_name,
+                self.total_time / 1_000,
+                self.total_time.saturating_sub(children) / 1_000,
+                percent(self.total_time.saturating_sub(children), self.total_time)
+            );
+            for (child, time) in self.children.iter() {
+                println!(
+                    " - {} {}µs {:.1}%",
+                    spans[*child as usize],
+                    time / 1_000,
+                    percent(*time, self.total_time)
+                );
+            }
+        }
+        for phase in self.phases.iter() {
+            print_histogram(
+                &self.span_name,
+                &format!("'{}' since enter", phase.message),
+                buckets,
+                &phase.since_enter,
+                print_latency_distribution,
+            );
+            print_histogram(
+                &self.span_name,
+                &format!("'{}' since previous", phase.message),
+                buckets,
+                &phase.since_previous,
+                print_latency_distribution,
+            );
+        }
+        for counter in self.counters.iter() {
+            print_histogram(
+                &self.span_name,
+                &counter.event.name,
+                buckets,
+                &counter.hist,
+                print_counters_distribution,
+            );
+            if counter.running < counter.enabled {
+                println!(
+                    "{} {}: multiplexed, counted {:.1}% of the enabled time. values are scaled",
+                    self.span_name,
+                    counter.event.name,
+                    counter.running as f64 * 100.0 / counter.enabled as f64
+                );
+            }
+        }
+        let Some(units) = self.units.as_ref() else {
+            return;
+        };
+        print_histogram(
+            &self.span_name,
+            &format!("latency ns per {} {}", UNITS_SCALE, units.field),
+            buckets,
+            &units.latency,
+            print_counters_distribution,
+        );
+        print_histogram(
+            &self.span_name,
+            &format!("{} per second", units.field),
+            buckets,
+            &units.throughput,
+            print_counters_distribution,
+        );
+        for counter in self.counters.iter() {
+            if let Some(hist) = counter.per_unit.as_ref() {
+                print_histogram(
+                    &self.span_name,
+                    &format!("{} per {} {}", counter.event.name, UNITS_SCALE, units.field),
+                    buckets,
+                    hist,
+                    print_counters_distribution,
+                );
+            }
+        }
+        if units.skipped > 0 {
+            println!(
+                "{} {}: {} spans without positive value are not normalized",
+                self.span_name, units.field, units.skipped
+            );
+        }
+    }
+}
+
+fn per_units(value: u64, units: u64) -> u64 {
+    (value as u128 * UNITS_SCALE as u128 / units as u128) as u64
+}
+
+/// Scales the counter delta by the ratio of enabled to running time, to account for the time
+/// when the counter wasn't scheduled on the pmu because it was multiplexed with other groups.
+/// Counter that wasn't running at all within the span can't be scaled.
+fn scale_counter(delta: u64, enabled: u64, running: u64) -> Option<u64> {
+    if running == 0 {
+        return (enabled == 0).then_some(delta);
+    }
+    if enabled <= running {
+        return Some(delta);
+    }
+    Some((delta as u128 * enabled as u128 / running as u128) as u64)
+}
+
+fn print_histogram(
+    span: &str,
+    kind: &str,
+    buckets: u64,
+    hist: &Histogram<u64>,
+    print_fn: impl Fn(IterationValue<u64>, u64),
+) {
+    println!(
+        "{} {}: samples {} min {} max {} mean {:.2} stdev {:.2} p80 {} p95 {}",
+        span,
+        kind,
+        hist.len(),
+        hist.min(),
+        hist.max(),
+        hist.mean(),
+        hist.stdev(),
+        hist.value_at_quantile(0.8),
+        hist.value_at_quantile(0.95)
+    );
+    if hist.is_empty() {
+        return;
+    }
+    let print_fn = |v| print_fn(v, hist.len());
+    hist.iter_linear(bucket_step(hist.min(), hist.max(), buckets))
+        .skip_while(|v| v.quantile() < 0.01)
+        .for_each(print_fn);
+}
+
+// step is at least 1, as histogram with the same values, such as counters that are always zero,
+// has no range to split
+fn bucket_step(min: u64, max: u64, buckets: u64) -> u64 {
+    (((max - min) as f64 / buckets as f64).ceil() as u64).max(1)
+}
+
+fn print_latency_distribution(v: IterationValue<u64>, total_count: u64) {
+    println!(
+        "{:4}µs | {:40} | {:4.1}th %-ile",
+        (v.value_iterated_to() + 1) / 1_000,
+        "*".repeat(
+            (v.count_since_last_iteration() as f64 * 50.0 / total_count as f64).ceil() as usize
+        ),
+        v.percentile(),
+    );
+}
+
+fn print_counters_distribution(v: IterationValue<u64>, total_count: u64) {
+    println!(
+        "{:10} | {:40} | {:4.1}th %-ile",
+        v.value_iterated_to(),
+        "*".repeat(
+            (v.count_since_last_iteration() as f64 * 50.0 / total_count as f64).ceil() as usize
+        ),
+        v.percentile(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{spans::SpanTracker, testing::event};
+
+    #[test]
+    fn scales_multiplexed_counter() {
+        assert_eq!(scale_counter(100, 1_000, 1_000), Some(100));
+        assert_eq!(scale_counter(100, 1_000, 250), Some(400));
+    }
+
+    #[test]
+    fn prints_histogram_with_same_values() {
+        assert_eq!(bucket_step(0, 0, 20), 1);
+        assert_eq!(bucket_step(0, 100, 20), 5);
+        let mut hist = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        hist.record_n(0, 10).unwrap();
+        print_histogram(
+            "span",
+            "major_faults",
+            20,
+            &hist,
+            print_counters_distribution,
+        );
+    }
+
+    #[test]
+    fn records_self_time_of_every_instance() {
+        let mut tracker = SpanTracker::default();
+        let mut histograms = SpanHistograms::new("parent".to_string(), std::iter::empty(), None);
+        // instance with a child
+        tracker.enter(&event(0, 0, 1, 100));
+        tracker.enter(&event(0, 1, 2, 110));
+        tracker.exit(&event(1, 1, 2, 150));
+        histograms.record_span(&tracker.exit(&event(1, 0, 1, 200)).unwrap());
+        // leaf instance
+        tracker.enter(&event(0, 0, 3, 300));
+        histograms.record_span(&tracker.exit(&event(1, 0, 3, 320)).unwrap());
+
+        assert_eq!(histograms.self_time.len(), 2);
+        assert_eq!(histograms.self_time.min(), 20);
+        assert_eq!(histograms.self_time.max(), 60);
+        assert_eq!(histograms.children.get(&1), Some(&40));
+    }
+
+    #[test]
+    fn skips_counter_that_was_not_running() {
+        assert_eq!(scale_counter(0, 1_000, 0), None);
+        assert_eq!(scale_counter(0, 0, 0), Some(0));
+    }
+}
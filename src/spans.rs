@@ -0,0 +1,396 @@
+use hashbrown::HashMap;
+use tracing::warn;
+
+use crate::{fields::merge_fields, Event};
+
+/// Matches exits with enters of the watched spans.
+///
+/// Every thread has a stack of spans that it entered, so that nested spans know their parent
+/// and time spent in children can be subtracted from the parent. The same span may be on the stack
+/// several times if it was re-entered, exit is matched with the innermost instance.
+///
+/// Spans that may exit on a different thread, for example when futures are moved between workers
+/// of async runtime, are tracked by tgid and span id instead. Nesting is not tracked in that mode,
+/// as spans of different threads can't be told apart from children.
+#[derive(Default)]
+pub struct SpanTracker {
+    threads: HashMap<u64, Vec<OpenSpan>>,
+    processes: Option<HashMap<(u32, u64), Vec<OpenSpan>>>,
+    // events are kept as phases of the span, otherwise only their level is checked
+    phases: bool,
+}
+
+struct OpenSpan {
+    enter: Event,
+    // total time of the completed children by name
+    children: Vec<(u8, u64)>,
+    phases: Vec<Phase>,
+    // values recorded on the span while it was open
+    recorded: Vec<u8>,
+    error_event: bool,
+}
+
+/// Tracing event emitted inside of the span.
+pub struct Phase {
+    pub message: String,
+    pub timestamp: u64,
+}
+
+/// Span with matched enter and exit.
+pub struct CompletedSpan {
+    pub enter: Event,
+    pub exit: Event,
+    /// Names of the spans from the outermost to this span.
+    pub path: Vec<u8>,
+    /// Total time of the watched children by name.
+    pub children: Vec<(u8, u64)>,
+    /// Span was entered again before it exited, e.g. by recursion.
+    pub reentered: bool,
+    /// Span exited while spans entered after it were still open.
+    pub out_of_order: bool,
+    /// Span exited on a different thread than it was entered.
+    pub handoff: bool,
+    /// Events emitted inside of the span, in the order they were emitted.
+    pub phases: Vec<Phase>,
+    /// Fields of the span on exit, with the values recorded while it was open.
+    pub fields: Vec<u8>,
+    /// Span emitted an ERROR event.
+    pub error_event: bool,
+}
+
+impl CompletedSpan {
+    pub fn latency(&self) -> u64 {
+        self.exit.timestamp.saturating_sub(self.enter.timestamp)
+    }
+
+    /// Time spent in the span excluding watched children.
+    pub fn self_time(&self) -> u64 {
+        let children = self.children.iter().map(|(_, time)| time).sum::<u64>();
+        self.latency().saturating_sub(children)
+    }
+}
+
+impl SpanTracker {
+    /// Tracker that matches exits on any thread of the process.
+    pub fn cross_thread() -> Self {
+        Self {
+            processes: Some(HashMap::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Keep events emitted inside of the span as its phases.
+    pub fn with_phases(mut self, phases: bool) -> Self {
+        self.phases = phases;
+        self
+    }
+
+    pub fn enter(&mut self, ev: &Event) {
+        let open = OpenSpan {
+            enter: *ev,
+            children: vec![],
+            phases: vec![],
+            recorded: vec![],
+            error_event: false,
+        };
+        if let Some(processes) = self.processes.as_mut() {
+            processes.entry(process_key(ev)).or_default().push(open);
+            return;
+        }
+        self.threads.entry(ev.pid_tgid).or_default().push(open);
+    }
+
+    /// Innermost span that is open on the thread. Spans are not attributed to threads in cross thread mode.
+    pub fn innermost(&self, pid_tgid: u64) -> Option<u8> {
+        self.threads
+            .get(&pid_tgid)
+            .and_then(|stack| stack.last())
+            .map(|open| open.enter.name_id)
+    }
+
+    /// Adds the event to the innermost instance of the open span. Events of the spans
+    /// that are not tracked, for example filtered by field values, are ignored.
+    pub fn event(&mut self, ev: &Event) {
+        let phases = self.phases;
+        if let Some(open) = self.open_span(ev) {
+            // levels are numbered from 1 for error
+            open.error_event |= ev.level == 1;
+            if !phases {
+                return;
+            }
+            let size = ev
+                .fields
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(ev.fields.len());
+            open.phases.push(Phase {
+                message: String::from_utf8_lossy(&ev.fields[..size]).into_owned(),
+                timestamp: ev.timestamp,
+            });
+        }
+    }
+
+    /// Merges values recorded on the span into the innermost instance of the open span.
+    /// Values recorded when the span is not entered are ignored.
+    pub fn record(&mut self, ev: &Event) {
+        if let Some(open) = self.open_span(ev) {
+            open.recorded = merge_fields(&open.recorded, &ev.fields);
+        }
+    }
+
+    fn open_span(&mut self, ev: &Event) -> Option<&mut OpenSpan> {
+        match self.processes.as_mut() {
+            Some(processes) => processes
+                .get_mut(&process_key(ev))
+                .and_then(|entered| entered.last_mut()),
+            None => self.threads.get_mut(&ev.pid_tgid).and_then(|stack| {
+                stack
+                    .iter_mut()
+                    .rev()
+                    .find(|open| open.enter.span_id == ev.span_id)
+            }),
+        }
+    }
+
+    pub fn exit(&mut self, ev: &Event) -> Option<CompletedSpan> {
+        self.close(ev, false)
+    }
+
+    /// Closes the span that didn't match predicates, its time is not added to the parent.
+    pub fn discard(&mut self, ev: &Event) {
+        self.close(ev, true);
+    }
+
+    fn close(&mut self, ev: &Event, discarded: bool) -> Option<CompletedSpan> {
+        if self.processes.is_some() {
+            return self.exit_cross_thread(ev);
+        }
+        let Some(stack) = self.threads.get_mut(&ev.pid_tgid) else {
+            warn!(
+                "missed opening event for span {}/{}",
+                ev.pid_tgid, ev.span_id
+            );
+            return None;
+        };
+        // exit is expected for the innermost span, but spans are not required to be properly nested
+        let Some(position) = stack
+            .iter()
+            .rposition(|open| open.enter.span_id == ev.span_id)
+        else {
+            warn!(
+                "missed opening event for span {}/{}",
+                ev.pid_tgid, ev.span_id
+            );
+            return None;
+        };
+        let out_of_order = position + 1 != stack.len();
+        let open = stack.remove(position);
+        let reentered = stack[..position]
+            .iter()
+            .any(|parent| parent.enter.span_id == ev.span_id);
+        let mut path = stack[..position]
+            .iter()
+            .map(|parent| parent.enter.name_id)
+            .collect::<Vec<_>>();
+        path.push(ev.name_id);
+        let completed = CompletedSpan {
+            enter: open.enter,
+            exit: *ev,
+            path,
+            children: open.children,
+            reentered,
+            out_of_order,
+            handoff: false,
+            phases: open.phases,
+            fields: merge_fields(&ev.fields, &open.recorded),
+            error_event: open.error_event,
+        };
+        if let Some(parent) = position
+            .checked_sub(1)
+            .filter(|_| !discarded)
+            .map(|parent| &mut stack[parent])
+        {
+            add_child(&mut parent.children, ev.name_id, completed.latency());
+        }
+        if stack.is_empty() {
+            self.threads.remove(&ev.pid_tgid);
+        }
+        Some(completed)
+    }
+
+    fn exit_cross_thread(&mut self, ev: &Event) -> Option<CompletedSpan> {
+        let processes = self.processes.as_mut().expect("cross thread mode");
+        let key = process_key(ev);
+        let Some(open) = processes.get_mut(&key).and_then(|entered| entered.pop()) else {
+            warn!(
+                "missed opening event for span {}/{}",
+                ev.pid_tgid, ev.span_id
+            );
+            return None;
+        };
+        let reentered = processes
+            .get(&key)
+            .is_some_and(|entered| !entered.is_empty());
+        if !reentered {
+            processes.remove(&key);
+        }
+        Some(CompletedSpan {
+            enter: open.enter,
+            exit: *ev,
+            path: vec![ev.name_id],
+            children: vec![],
+            reentered,
+            out_of_order: false,
+            handoff: open.enter.pid_tgid != ev.pid_tgid,
+            phases: open.phases,
+            fields: merge_fields(&ev.fields, &open.recorded),
+            error_event: open.error_event,
+        })
+    }
+}
+
+impl SpanTracker {
+    /// Names of the spans that were entered but not exited yet.
+    pub fn open_spans(&self) -> impl Iterator<Item = u8> + '_ {
+        let threads = self
+            .threads
+            .values()
+            .flat_map(|stack| stack.iter().map(|open| open.enter.name_id));
+        let processes = self
+            .processes
+            .iter()
+            .flat_map(|processes| processes.values().flatten().map(|open| open.enter.name_id));
+        threads.chain(processes)
+    }
+}
+
+// upper half of pid_tgid is the process id
+fn process_key(ev: &Event) -> (u32, u64) {
+    ((ev.pid_tgid >> 32) as u32, ev.span_id)
+}
+
+fn add_child(children: &mut Vec<(u8, u64)>, name_id: u8, time: u64) {
+    match children.iter_mut().find(|(child, _)| *child == name_id) {
+        Some((_, total)) => *total += time,
+        None => children.push((name_id, time)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{event, PID_TGID};
+
+    fn message(span_id: u64, level: u8, message: &str, timestamp: u64) -> Event {
+        let mut ev = Event {
+            level,
+            ..event(4, 0, span_id, timestamp)
+        };
+        ev.fields[..message.len()].copy_from_slice(message.as_bytes());
+        ev
+    }
+
+    #[test]
+    fn subtracts_children_from_parent() {
+        let mut tracker = SpanTracker::default();
+        tracker.enter(&event(0, 0, 1, 100));
+        tracker.enter(&event(0, 1, 2, 110));
+        let child = tracker.exit(&event(1, 1, 2, 140)).unwrap();
+        assert_eq!(child.path, [0, 1]);
+        assert!(!child.out_of_order);
+        let parent = tracker.exit(&event(1, 0, 1, 200)).unwrap();
+        assert_eq!(parent.children, [(1, 30)]);
+        assert_eq!(parent.self_time(), 70);
+        assert_eq!(tracker.open_spans().count(), 0);
+    }
+
+    #[test]
+    fn discards_filtered_child() {
+        let mut tracker = SpanTracker::default();
+        tracker.enter(&event(0, 0, 1, 100));
+        tracker.enter(&event(0, 1, 2, 110));
+        tracker.discard(&event(7, 1, 2, 140));
+        let parent = tracker.exit(&event(1, 0, 1, 200)).unwrap();
+        assert!(parent.children.is_empty());
+        assert_eq!(parent.self_time(), 100);
+        assert_eq!(tracker.open_spans().count(), 0);
+    }
+
+    #[test]
+    fn matches_exit_with_innermost_reentered_span() {
+        let mut tracker = SpanTracker::default();
+        tracker.enter(&event(0, 0, 1, 100));
+        tracker.enter(&event(0, 0, 1, 110));
+        let inner = tracker.exit(&event(1, 0, 1, 120)).unwrap();
+        assert!(inner.reentered);
+        assert_eq!(inner.latency(), 10);
+        let outer = tracker.exit(&event(1, 0, 1, 150)).unwrap();
+        assert!(!outer.reentered);
+        assert_eq!(outer.latency(), 50);
+    }
+
+    #[test]
+    fn ignores_exit_without_enter() {
+        let mut tracker = SpanTracker::default();
+        assert!(tracker.exit(&event(1, 0, 1, 100)).is_none());
+    }
+
+    #[test]
+    fn matches_exit_on_another_thread() {
+        let mut tracker = SpanTracker::cross_thread();
+        tracker.enter(&event(0, 0, 1, 100));
+        let exit = Event {
+            pid_tgid: PID_TGID + 1,
+            ..event(1, 0, 1, 150)
+        };
+        let span = tracker.exit(&exit).unwrap();
+        assert!(span.handoff);
+        assert_eq!(span.latency(), 50);
+    }
+
+    #[test]
+    fn keeps_events_as_phases() {
+        let mut tracker = SpanTracker::default().with_phases(true);
+        tracker.enter(&event(0, 0, 1, 100));
+        tracker.event(&message(1, 3, "parsed", 120));
+        tracker.event(&message(1, 3, "written", 160));
+        // event of the span that is not open
+        tracker.event(&message(2, 1, "failed", 170));
+        let span = tracker.exit(&event(1, 0, 1, 200)).unwrap();
+        let phases = span
+            .phases
+            .iter()
+            .map(|phase| (phase.message.as_str(), phase.timestamp))
+            .collect::<Vec<_>>();
+        assert_eq!(phases, [("parsed", 120), ("written", 160)]);
+        assert!(!span.error_event);
+    }
+
+    #[test]
+    fn merges_recorded_values_into_exit_fields() {
+        let mut tracker = SpanTracker::default();
+        tracker.enter(&event(0, 0, 1, 100));
+        let mut record = event(6, 0, 1, 110);
+        record.fields[..11].copy_from_slice(b"status=200\0");
+        tracker.record(&record);
+        // value recorded on the span that is not open
+        let mut other = event(6, 0, 2, 120);
+        other.fields[..11].copy_from_slice(b"status=500\0");
+        tracker.record(&other);
+
+        let mut exit = event(1, 0, 1, 200);
+        exit.fields[..19].copy_from_slice(b"method=GET\0status=\0");
+        let span = tracker.exit(&exit).unwrap();
+        assert_eq!(span.fields, b"method=GET\0status=200\0");
+    }
+
+    #[test]
+    fn flags_error_event_without_phases() {
+        let mut tracker = SpanTracker::default();
+        tracker.enter(&event(0, 0, 1, 100));
+        tracker.event(&message(1, 1, "failed", 120));
+        let span = tracker.exit(&event(1, 0, 1, 200)).unwrap();
+        assert!(span.phases.is_empty());
+        assert!(span.error_event);
+    }
+}
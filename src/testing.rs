@@ -0,0 +1,15 @@
+use crate::Event;
+
+pub const PID_TGID: u64 = 10 << 32 | 11;
+
+/// Event of the same thread, as it is sent by bpf for the span.
+pub fn event(r#type: u8, name_id: u8, span_id: u64, timestamp: u64) -> Event {
+    Event {
+        r#type,
+        name_id,
+        span_id,
+        pid_tgid: PID_TGID,
+        timestamp,
+        ..Default::default()
+    }
+}
@@ -1,46 +1,834 @@
-use probe::probe;
-use tracing::{level_filters::LevelFilter, span, Subscriber};
+use std::{
+    cell::OnceCell,
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use probe::probe_lazy;
+use tracing::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
 use tracing_subscriber::{
+    filter::{Filtered, Targets},
     layer::{Context, SubscriberExt},
     registry::LookupSpan,
+    util::{SubscriberInitExt, TryInitError},
     Layer,
 };
 
+#[doc(hidden)]
+pub use probe::probe_lazy as __probe_lazy;
+
+/// Records the value of the metric, such as batch size or queue depth, with `perfspan:value` probe.
+///
+/// Value is evaluated only if perfspan is attached to the probe, perfspan builds a histogram of the values
+/// for every metric. Name of the metric must be a string literal.
+///
+/// ```text
+/// tracing_perfspan::record!("batch_size", batch.len());
+/// ```
+#[macro_export]
+macro_rules! record {
+    ($name:literal, $value:expr) => {
+        $crate::__probe_lazy!(
+            perfspan,
+            value,
+            $name.len() as u16,
+            $name.as_ptr(),
+            ($value) as u64
+        )
+    };
+}
+
 /// Initialize tracing with PerfspanLayer.
 ///
 /// Uses DEBUG as the default level for spans, but can be overridden by setting
 /// the PERF_SPAN_LEVEL environment variable.
 pub fn init() {
+    try_init().expect("setting default subscriber failed");
+}
+
+/// Same as [`init`], but returns an error if the global default subscriber is already set.
+pub fn try_init() -> Result<(), TryInitError> {
     let env_filter = tracing_subscriber::EnvFilter::builder()
         .with_default_directive(LevelFilter::DEBUG.into())
         .with_env_var("PERF_SPAN_LEVEL")
         .from_env_lossy();
-    let layer = PerfspanLayer {}.with_filter(env_filter);
-    let subscriber = tracing_subscriber::Registry::default().with(layer);
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    let layer = PerfspanLayer::default().with_filter(env_filter);
+    tracing_subscriber::Registry::default()
+        .with(layer)
+        .try_init()
+}
+
+/// Layer that fires usdt probes for spans and events.
+///
+/// Every probe is guarded by a semaphore, so enabled probes cost nothing until perfspan attaches to them.
+/// Use [`PerfspanLayer::builder`] to add the layer to the registry shared with other layers.
+#[derive(Debug, Clone)]
+pub struct PerfspanLayer {
+    enter: bool,
+    exit: bool,
+    new_span: bool,
+    close: bool,
+    events: bool,
+    record: bool,
+    follows_from: bool,
+    callsite: bool,
+    // fields passed to the probes, all fields if empty
+    fields: Vec<String>,
+    // interned callsites, address of the info is the id of the callsite
+    callsites: Arc<RwLock<HashMap<Identifier, &'static CallsiteInfo>>>,
+}
+
+impl Default for PerfspanLayer {
+    fn default() -> Self {
+        Self {
+            enter: true,
+            exit: true,
+            new_span: true,
+            close: true,
+            events: true,
+            record: true,
+            follows_from: true,
+            callsite: true,
+            fields: Vec::new(),
+            callsites: Arc::default(),
+        }
+    }
+}
+
+impl PerfspanLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    fn callsite(&self, metadata: &'static Metadata<'static>) -> &'static CallsiteInfo {
+        let identifier = metadata.callsite();
+        if let Some(info) = self
+            .callsites
+            .read()
+            .expect("not poisoned")
+            .get(&identifier)
+        {
+            return info;
+        }
+        self.callsites
+            .write()
+            .expect("not poisoned")
+            .entry(identifier)
+            .or_insert_with(|| CallsiteInfo::leak(metadata, self.callsite))
+    }
+
+    fn callsite_id(&self, metadata: &'static Metadata<'static>) -> u64 {
+        self.callsite(metadata).id()
+    }
+}
+
+/// Metadata of the callsite, it is leaked and never freed, and the address is the id of the callsite.
+///
+/// Probes pass only the id, names are resolved by perfspan by reading this struct from the memory
+/// of the process. The layout must match the one read by perfspan.
+#[repr(C)]
+#[derive(Debug)]
+struct CallsiteInfo {
+    name: u64,
+    name_size: u64,
+    target: u64,
+    target_size: u64,
+    file: u64,
+    file_size: u64,
+    line: u64,
+    // set by perfspan when it resolves the callsite, fields are formatted only for the watched spans
+    watched: AtomicU64,
+}
+
+impl CallsiteInfo {
+    fn leak(metadata: &'static Metadata<'static>, callsite: bool) -> &'static Self {
+        let file = metadata.file().filter(|_| callsite).unwrap_or("");
+        Box::leak(Box::new(Self {
+            name: metadata.name().as_ptr() as u64,
+            name_size: metadata.name().len() as u64,
+            target: metadata.target().as_ptr() as u64,
+            target_size: metadata.target().len() as u64,
+            file: file.as_ptr() as u64,
+            file_size: file.len() as u64,
+            line: metadata.line().filter(|_| callsite).unwrap_or(0) as u64,
+            watched: AtomicU64::new(0),
+        }))
+    }
+
+    fn id(&'static self) -> u64 {
+        self as *const Self as u64
+    }
+
+    fn watched(&self) -> bool {
+        self.watched.load(Ordering::Relaxed) != 0
+    }
+}
+
+/// Builder for [`PerfspanLayer`] with a per-layer filter, so that it can be added to the registry
+/// together with fmt or opentelemetry layers without affecting what they record.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    layer: PerfspanLayer,
+    filter: Targets,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            layer: PerfspanLayer::default(),
+            filter: Targets::new().with_default(LevelFilter::DEBUG),
+        }
+    }
+}
+
+impl Builder {
+    /// Level for spans and events from targets without explicit level, DEBUG by default.
+    pub fn with_default_level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.filter = self.filter.with_default(level);
+        self
+    }
+
+    /// Level for spans and events from the target and its modules.
+    pub fn with_target(mut self, target: impl Into<String>, level: impl Into<LevelFilter>) -> Self {
+        self.filter = self.filter.with_target(target, level);
+        self
+    }
+
+    /// Replaces levels and targets, for example parsed from `my_crate=trace,h2=off`.
+    pub fn with_filter(mut self, filter: Targets) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Fire `perfspan:enter` when the span is entered.
+    pub fn with_enter(mut self, enabled: bool) -> Self {
+        self.layer.enter = enabled;
+        self
+    }
+
+    /// Fire `perfspan:exit` when the span is exited.
+    pub fn with_exit(mut self, enabled: bool) -> Self {
+        self.layer.exit = enabled;
+        self
+    }
+
+    /// Fire `perfspan:new` when the span is created.
+    ///
+    /// Fields of the span are formatted only when perfspan is attached to this probe,
+    /// so spans have no fields if it is disabled.
+    pub fn with_new_span(mut self, enabled: bool) -> Self {
+        self.layer.new_span = enabled;
+        self
+    }
+
+    /// Fire `perfspan:close` when the span is closed.
+    pub fn with_close(mut self, enabled: bool) -> Self {
+        self.layer.close = enabled;
+        self
+    }
+
+    /// Fire `perfspan:event` for every event.
+    pub fn with_events(mut self, enabled: bool) -> Self {
+        self.layer.events = enabled;
+        self
+    }
+
+    /// Fire `perfspan:record` when values are recorded on the span after it was created.
+    ///
+    /// Recorded values are merged into the fields of the span only when perfspan is attached
+    /// to this probe.
+    pub fn with_record(mut self, enabled: bool) -> Self {
+        self.layer.record = enabled;
+        self
+    }
+
+    /// Fire `perfspan:follows` when the span is linked to the span that caused it.
+    pub fn with_follows_from(mut self, enabled: bool) -> Self {
+        self.layer.follows_from = enabled;
+        self
+    }
+
+    /// Pass only these fields of the span to the probes, such as the ones used for grouping and predicates.
+    ///
+    /// All fields are passed by default, and fields that don't fit into 128 bytes are not seen by perfspan.
+    pub fn with_fields<I, T>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.layer.fields = fields.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Pass source file and line of the span to the probes, so that perfspan can print them.
+    pub fn with_callsite(mut self, enabled: bool) -> Self {
+        self.layer.callsite = enabled;
+        self
+    }
+
+    pub fn build<S>(self) -> Filtered<PerfspanLayer, Targets, S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        self.layer.with_filter(self.filter)
+    }
+
+    /// Sets global default subscriber with only this layer, returns an error if it is already set.
+    pub fn try_init(self) -> Result<(), TryInitError> {
+        tracing_subscriber::registry().with(self.build()).try_init()
+    }
+}
+
+/// Fields recorded on the span, formatted as `name=value` pairs terminated by nul byte.
+///
+/// They are passed to the probes so that perfspan can group and filter spans by field values.
+/// Strings, numbers and booleans are passed before the values formatted with Debug, so that large
+/// values don't push them out of the part read by bpf.
+#[derive(Default)]
+struct Fields {
+    values: String,
+    formatted: String,
+}
+
+impl Fields {
+    /// Replaces values of the fields that were recorded again and appends the new ones.
+    fn merge(&mut self, recorded: &Fields) {
+        let names = recorded.names().collect::<Vec<_>>();
+        self.values = without(&self.values, &names) + &recorded.values;
+        self.formatted = without(&self.formatted, &names) + &recorded.formatted;
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        pairs(&self.values)
+            .chain(pairs(&self.formatted))
+            .map(field_name)
+    }
+
+    /// Bytes passed to the probes, truncated to the size read by bpf.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAX_FIELDS_SIZE);
+        for part in [&self.values, &self.formatted] {
+            let size = part.len().min(MAX_FIELDS_SIZE - bytes.len());
+            bytes.extend_from_slice(&part.as_bytes()[..size]);
+        }
+        bytes
+    }
+}
+
+fn pairs(fields: &str) -> impl Iterator<Item = &str> {
+    fields.split_terminator('\0')
+}
+
+fn field_name(pair: &str) -> &str {
+    pair.split_once('=').map_or(pair, |(name, _)| name)
+}
+
+// pairs of the fields without the ones with the names
+fn without(fields: &str, names: &[&str]) -> String {
+    let mut kept = String::with_capacity(fields.len());
+    for pair in pairs(fields).filter(|pair| !names.contains(&field_name(pair))) {
+        kept.push_str(pair);
+        kept.push('\0');
+    }
+    kept
+}
+
+/// Records fields of the span, only the selected ones if the layer passes a subset of the fields.
+struct FieldsVisitor<'a> {
+    fields: Fields,
+    selected: &'a [String],
+}
+
+impl<'a> FieldsVisitor<'a> {
+    fn new(layer: &'a PerfspanLayer) -> Self {
+        Self {
+            fields: Fields::default(),
+            selected: &layer.fields,
+        }
+    }
+
+    fn is_selected(&self, field: &Field) -> bool {
+        self.selected.is_empty() || self.selected.iter().any(|name| name == field.name())
+    }
+
+    fn record_value(&mut self, field: &Field, value: &dyn fmt::Display) {
+        if self.is_selected(field) {
+            let _ = write!(self.fields.values, "{}={}\0", field.name(), value);
+        }
+    }
 }
 
-pub struct PerfspanLayer {}
+impl Visit for FieldsVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_value(field, &value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_value(field, &value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_value(field, &value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record_value(field, &value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record_value(field, &value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.is_selected(field) {
+            let _ = write!(self.fields.formatted, "{}={:?}\0", field.name(), value);
+        }
+    }
+}
 
 impl<S> Layer<S> for PerfspanLayer
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_span() {
+            let id = self.callsite_id(metadata);
+            probe_lazy!(perfspan, register, id);
+        }
+        Interest::always()
+    }
+
+    // perfspan attaches to the probe only when it reads fields, otherwise they are not formatted.
+    // fields of the spans that perfspan doesn't watch are not formatted either
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if !self.new_span {
+            return;
+        }
+        let args = OnceCell::new();
+        let args = || {
+            args.get_or_init(|| {
+                let watched = self.callsite(attrs.metadata()).watched();
+                if let Some(span) = ctx.span(id).filter(|_| watched) {
+                    let mut fields = FieldsVisitor::new(self);
+                    attrs.record(&mut fields);
+                    span.extensions_mut().insert(fields.fields);
+                }
+                SpanArgs::new(self, id, &ctx)
+            })
+        };
+        probe_lazy!(
+            perfspan,
+            new,
+            args().span_id,
+            args().callsite,
+            args().fields.len() as u16,
+            args().fields.as_ptr()
+        );
+    }
+
+    // fields declared as Empty are usually filled when the work is done, they are merged into the fields
+    // passed on exit, and fired separately as they may not fit into the fields read by bpf
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if !self.record {
+            return;
+        }
+        // values are merged only if perfspan is attached and incremented the semaphore of the probe
+        let args = OnceCell::new();
+        let args = || args.get_or_init(|| RecordArgs::new(self, id, values, &ctx));
+        probe_lazy!(
+            perfspan,
+            record,
+            id.into_u64(),
+            args().callsite,
+            args().fields.len() as u16,
+            args().fields.as_ptr()
+        );
+    }
+
+    fn on_follows_from(&self, span: &span::Id, follows: &span::Id, ctx: Context<'_, S>) {
+        if !self.follows_from {
+            return;
+        }
+        let callsite = |id: &span::Id| {
+            ctx.span(id)
+                .map_or(0, |span| self.callsite_id(span.metadata()))
+        };
+        probe_lazy!(
+            perfspan,
+            follows,
+            span.into_u64(),
+            callsite(span),
+            follows.into_u64(),
+            callsite(follows)
+        );
+    }
+
     fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
-        if let Some(span) = ctx.span(id) {
-            let name_size = span.name().len() as u16;
-            let name = span.name().as_ptr();
-            let span_id = span.id().into_u64();
-            probe!(perfspan, enter, span_id, name_size, name);
+        if !self.enter {
+            return;
         }
+        // span is looked up only if perfspan is attached and incremented the semaphore of the probe
+        let args = OnceCell::new();
+        let args = || args.get_or_init(|| SpanArgs::new(self, id, &ctx));
+        probe_lazy!(
+            perfspan,
+            enter,
+            args().span_id,
+            args().callsite,
+            args().fields.len() as u16,
+            args().fields.as_ptr()
+        );
     }
 
     fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
-        if let Some(span) = ctx.span(id) {
-            let name_size = span.name().len() as u16;
-            let name = span.name().as_ptr();
-            let span_id = span.id().into_u64();
-            probe!(perfspan, exit, span_id, name_size, name);
+        if !self.exit {
+            return;
         }
+        let args = OnceCell::new();
+        let args = || args.get_or_init(|| SpanArgs::new(self, id, &ctx));
+        probe_lazy!(
+            perfspan,
+            exit,
+            args().span_id,
+            args().callsite,
+            args().fields.len() as u16,
+            args().fields.as_ptr()
+        );
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        if !self.close {
+            return;
+        }
+        probe_lazy!(perfspan, close, id.into_u64());
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !self.events {
+            return;
+        }
+        let args = OnceCell::new();
+        let args = || args.get_or_init(|| EventArgs::new(self, event, &ctx));
+        probe_lazy!(
+            perfspan,
+            event,
+            args().span_id,
+            args().callsite,
+            args().level,
+            args().message_size,
+            args().message.as_ptr()
+        );
+    }
+}
+
+/// Arguments of the event probe. Span id and callsite of the span are zero for events outside of spans.
+///
+/// Event is passed with the nearest enclosing span that perfspan watches, or with the innermost span
+/// if none of them is watched yet.
+struct EventArgs {
+    span_id: u64,
+    callsite: u64,
+    level: u8,
+    message_size: u16,
+    message: String,
+}
+
+impl EventArgs {
+    fn new<S>(layer: &PerfspanLayer, event: &Event<'_>, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let mut message = Message::default();
+        event.record(&mut message);
+        if message.0.is_empty() {
+            message.0 = event.metadata().name().to_string();
+        }
+        let span = ctx.event_span(event).map(|innermost| {
+            innermost
+                .scope()
+                .find(|span| layer.callsite(span.metadata()).watched())
+                .unwrap_or(innermost)
+        });
+        Self {
+            span_id: span.as_ref().map_or(0, |span| span.id().into_u64()),
+            callsite: span
+                .as_ref()
+                .map_or(0, |span| layer.callsite_id(span.metadata())),
+            level: level_number(event.metadata().level()),
+            message_size: message.0.len().min(u16::MAX as usize) as u16,
+            message: message.0,
+        }
+    }
+}
+
+// levels are numbered as in log crate, from 1 for error to 5 for trace
+fn level_number(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    }
+}
+
+/// Message of the event, or the name of the event if it has no message.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+/// Arguments of the record probe, values are merged into the fields of the span when they are created.
+struct RecordArgs {
+    callsite: u64,
+    fields: Vec<u8>,
+}
+
+impl RecordArgs {
+    fn new<S>(
+        layer: &PerfspanLayer,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: &Context<'_, S>,
+    ) -> Self
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return Self {
+                callsite: 0,
+                fields: Vec::new(),
+            };
+        };
+        let callsite = layer.callsite(span.metadata());
+        if !callsite.watched() {
+            return Self {
+                callsite: callsite.id(),
+                fields: Vec::new(),
+            };
+        }
+        let mut recorded = FieldsVisitor::new(layer);
+        values.record(&mut recorded);
+        let fields = recorded.fields.to_bytes();
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<Fields>() {
+            Some(span_fields) => span_fields.merge(&recorded.fields),
+            None => extensions.insert(recorded.fields),
+        }
+        Self {
+            callsite: callsite.id(),
+            fields,
+        }
+    }
+}
+
+// this value should be consistent with value set in perfspan.h
+const MAX_FIELDS_SIZE: usize = 128;
+
+/// Arguments of the span probes. Fields are copied out of the span, as values recorded concurrently
+/// replace the buffer while the probe fires.
+struct SpanArgs {
+    span_id: u64,
+    callsite: u64,
+    fields: Vec<u8>,
+}
+
+impl SpanArgs {
+    fn new<S>(layer: &PerfspanLayer, id: &span::Id, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Some(span) = ctx.span(id) else {
+            // unknown callsite doesn't match any of the watched spans
+            return Self {
+                span_id: id.into_u64(),
+                callsite: 0,
+                fields: Vec::new(),
+            };
+        };
+        let fields = span
+            .extensions()
+            .get::<Fields>()
+            .map_or(Vec::new(), Fields::to_bytes);
+        Self {
+            span_id: id.into_u64(),
+            callsite: layer.callsite_id(span.metadata()),
+            fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // records fields of the event with the visitor used for spans, as probes are not enabled in tests
+    struct Capture {
+        layer: PerfspanLayer,
+        fields: Arc<Mutex<Fields>>,
+    }
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut fields = FieldsVisitor::new(&self.layer);
+            event.record(&mut fields);
+            *self.fields.lock().unwrap() = fields.fields;
+        }
+    }
+
+    fn capture(layer: PerfspanLayer, event: impl FnOnce()) -> Fields {
+        let fields = Arc::new(Mutex::new(Fields::default()));
+        let capture = Capture {
+            layer,
+            fields: fields.clone(),
+        };
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(capture), event);
+        let fields = std::mem::take(&mut *fields.lock().unwrap());
+        fields
+    }
+
+    fn fields(values: &str, formatted: &str) -> Fields {
+        Fields {
+            values: values.to_string(),
+            formatted: formatted.to_string(),
+        }
+    }
+
+    #[test]
+    fn passes_values_before_formatted() {
+        let req = vec![0u8; 100];
+        let fields = capture(PerfspanLayer::default(), || {
+            tracing::info!(req = ?req, method = "GET", status = 200, cached = false);
+        });
+        assert_eq!(fields.values, "method=GET\0status=200\0cached=false\0");
+        assert!(fields.formatted.starts_with("req=[0, 0"));
+        assert!(fields.to_bytes().starts_with(b"method=GET\0"));
+        assert_eq!(fields.to_bytes().len(), MAX_FIELDS_SIZE);
+    }
+
+    #[test]
+    fn passes_only_selected_fields() {
+        let layer = PerfspanLayer::builder()
+            .with_fields(["method", "tenant"])
+            .layer;
+        let fields = capture(layer, || {
+            tracing::info!(req = ?"body", method = "GET", tenant = ?"acme", status = 200);
+        });
+        assert_eq!(fields.values, "method=GET\0");
+        assert_eq!(fields.formatted, "tenant=\"acme\"\0");
+    }
+
+    #[test]
+    fn merges_recorded_values() {
+        let mut span = fields("method=GET\0status=0\0", "req=[]\0");
+        span.merge(&fields("status=200\0bytes=10\0", ""));
+        assert_eq!(span.values, "method=GET\0status=200\0bytes=10\0");
+        assert_eq!(span.formatted, "req=[]\0");
+        // value recorded with Debug replaces the one recorded as string
+        span.merge(&fields("", "method=Get\0"));
+        assert_eq!(span.values, "status=200\0bytes=10\0");
+        assert_eq!(span.formatted, "req=[]\0method=Get\0");
+    }
+
+    #[test]
+    fn truncates_fields_to_the_size_read_by_bpf() {
+        let large = format!("req={}\0", "x".repeat(200));
+        let mut span = fields("method=GET\0", &large);
+        assert_eq!(span.to_bytes().len(), MAX_FIELDS_SIZE);
+        assert!(span.to_bytes().starts_with(b"method=GET\0req=xx"));
+        // recorded values are kept in front of the large formatted value
+        span.merge(&fields("status=200\0", ""));
+        assert!(span
+            .to_bytes()
+            .starts_with(b"method=GET\0status=200\0req=xx"));
+        assert_eq!(span.to_bytes().len(), MAX_FIELDS_SIZE);
+
+        let values = "v=1\0".repeat(40);
+        assert_eq!(
+            fields(&values, "").to_bytes(),
+            &values.as_bytes()[..MAX_FIELDS_SIZE]
+        );
+    }
+
+    #[test]
+    fn builder_selects_probes() {
+        let layer = PerfspanLayer::builder()
+            .with_enter(false)
+            .with_record(false)
+            .with_events(false)
+            .with_callsite(false)
+            .layer;
+        assert!(!layer.enter && !layer.record && !layer.events && !layer.callsite);
+        assert!(layer.exit && layer.new_span && layer.close && layer.follows_from);
+        assert!(layer.fields.is_empty());
+    }
+
+    // passes the span of the event probe, as probes are not enabled in tests
+    struct CaptureSpan {
+        layer: PerfspanLayer,
+        span_id: Arc<Mutex<u64>>,
+    }
+
+    impl<S> Layer<S> for CaptureSpan
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            *self.span_id.lock().unwrap() = EventArgs::new(&self.layer, event, &ctx).span_id;
+        }
+    }
+
+    #[test]
+    fn attributes_event_to_nearest_watched_span() {
+        let layer = PerfspanLayer::default();
+        let span_id = Arc::new(Mutex::new(0));
+        let capture = CaptureSpan {
+            layer: layer.clone(),
+            span_id: span_id.clone(),
+        };
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(capture), || {
+            let outer = tracing::info_span!("outer");
+            let _outer = outer.enter();
+            let inner = tracing::info_span!("inner");
+            let _inner = inner.enter();
+            tracing::info!("parsed");
+            assert_eq!(*span_id.lock().unwrap(), inner.id().unwrap().into_u64());
+
+            layer
+                .callsite(outer.metadata().unwrap())
+                .watched
+                .store(1, Ordering::Relaxed);
+            tracing::info!("written");
+            assert_eq!(*span_id.lock().unwrap(), outer.id().unwrap().into_u64());
+        });
     }
 }